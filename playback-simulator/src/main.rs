@@ -1,12 +1,19 @@
 use embedded_graphics::{
-    image::Image,
+    image::{Image, ImageRaw},
     pixelcolor::BinaryColor,
     prelude::*,
 };
 use embedded_graphics_simulator::{
     BinaryColorTheme, OutputSettingsBuilder, SimulatorDisplay, SimulatorEvent, Window,
 };
-use monochrome_quadtree::dec::{video::VideoSlice, LeafParserV1, LeafParserV2, Decoder};
+use monochrome_quadtree::{
+    dec::{
+        raw::{Framebuffer, Layout},
+        video::{Video, VideoSlice},
+        Decoder, LeafParserV1, LeafParserV2, LeafParserV3, LeafParserV4,
+    },
+    Resolution,
+};
 use std::{
     env::args,
     error::Error,
@@ -16,16 +23,11 @@ use std::{
     time::{Duration, Instant},
 };
 
-//const W: u32 = 128;
-//const H: u32 = 64;
-
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut args = args().skip(1);
-    let mode = args.next().unwrap();
-    let format = args.next().unwrap();
-    let file = args.next().unwrap();
-
-    let display = SimulatorDisplay::<BinaryColor>::new(Size::new(128, 64));
+    let mut args: Vec<_> = args().skip(1).collect();
+    let file = args.pop().unwrap();
+    let mode = args.get(0).cloned();
+    let format = args.get(1).cloned();
 
     let settings = OutputSettingsBuilder::new()
         .theme(BinaryColorTheme::OledWhite)
@@ -34,36 +36,54 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut source = BufReader::new(File::open(file)?);
 
-    let version = match format.as_str() {
-        "-1" => 1,
-        "-2" => 2,
-        _ => panic!("Invalid format version")
-    };
-
     let mut data = Vec::new();
     source.read_to_end(&mut data)?;
 
-    match mode.as_str() {
-        "-v" | "--video" => if version == 1 {
-            video::<LeafParserV1>(display, window, &data)
-        } else {
-            video::<LeafParserV2>(display, window, &data)
+    // containers describe themselves, bare streams need the format version
+    if let Ok(video) = Video::new(&data) {
+        let header = video.header();
+        let display = new_display(header.resolution);
+        return play(display, window, video.frames(), header.frame_rate);
+    }
+
+    let version = match format.as_deref() {
+        Some("-1") => 1,
+        Some("-2") => 2,
+        Some("-3") => 3,
+        Some("-4") => 4,
+        _ => panic!("Invalid format version")
+    };
+    let display = new_display(Resolution::default());
+
+    match mode.as_deref().unwrap_or_default() {
+        "-v" | "--video" => match version {
+            1 => play(display, window, VideoSlice::<LeafParserV1>::new(&data), 30),
+            2 => play(display, window, VideoSlice::<LeafParserV2>::new(&data), 30),
+            3 => play(display, window, VideoSlice::<LeafParserV3>::new(&data), 30),
+            _ => play(display, window, VideoSlice::<LeafParserV4>::new(&data), 30),
         },
-        "-i" | "--image" => if version == 1 { 
-            img::<LeafParserV1>(display, window, &data)
-        } else {
-            img::<LeafParserV2>(display, window, &data)
+        "-i" | "--image" => match version {
+            1 => img::<LeafParserV1>(display, window, &data),
+            2 => img::<LeafParserV2>(display, window, &data),
+            3 => img::<LeafParserV3>(display, window, &data),
+            _ => img::<LeafParserV4>(display, window, &data),
         },
         _ => panic!("Wrong argument"),
     }
 }
 
+fn new_display(res: Resolution) -> SimulatorDisplay<BinaryColor> {
+    SimulatorDisplay::new(Size::new(res.width() as u32, res.height() as u32))
+}
+
 fn img<'a, D: Decoder<'a>>(
     mut display: SimulatorDisplay<BinaryColor>,
     mut window: Window,
     data: &'a[u8]
 ) -> Result<(), Box<dyn Error>> {
-    let raw = D::from_buf(&data).unwrap().drawable();
+    let dec = D::from_buf(&data).unwrap();
+    dec.validate().map_err(|e| format!("Invalid image: {}", e))?;
+    let raw = dec.drawable();
     let img = Image::new(&raw, Point::zero());
 
     img.draw(&mut display)?;
@@ -72,18 +92,22 @@ fn img<'a, D: Decoder<'a>>(
     Ok(())
 }
 
-fn video<'a, D: Decoder<'a>>(
+fn play<'a, D: Decoder<'a>>(
     mut display: SimulatorDisplay<BinaryColor>,
     mut window: Window,
-    data: &'a [u8]
+    iter: VideoSlice<'a, D>,
+    frame_rate: u16,
 ) -> Result<(), Box<dyn Error>> {
     window.update(&display);
 
+    // frames are drawn over the previous ones, which shifted frames move first
+    let resolution = iter.resolution();
+    let mut buf = vec![0; resolution.buf_len()];
+    let mut fb = Framebuffer::new(&mut buf, resolution, Layout::RowMajor).unwrap();
+
     let mut frame = 0u64;
     let started = Instant::now();
 
-    let iter = VideoSlice::<D>::new(&data);
-
     for i in iter {
         for event in window.events() {
             if let SimulatorEvent::Quit = event {
@@ -91,7 +115,10 @@ fn video<'a, D: Decoder<'a>>(
             }
         }
 
-        let raw = i.drawable();
+        let frame_data = i.map_err(|e| format!("Invalid frame {}: {}", frame, e))?;
+        frame_data.validate().map_err(|e| format!("Invalid frame {}: {}", frame, e))?;
+        fb.draw(&frame_data).map_err(|e| format!("Invalid frame {}: {}", frame, e))?;
+        let raw = ImageRaw::<BinaryColor>::new(fb.as_bytes(), resolution.width() as u32);
         let img = Image::new(&raw, Point::zero());
 
         img.draw(&mut display)?;
@@ -99,7 +126,7 @@ fn video<'a, D: Decoder<'a>>(
 
         frame += 1;
         let elapsed = started.elapsed().as_millis() as u64;
-        let expected = 1000 / 30 * frame;
+        let expected = 1000 * frame / frame_rate.max(1) as u64;
         let sleep_ms = expected.checked_sub(elapsed).unwrap_or(0);
 
         sleep(Duration::from_millis(sleep_ms));
@@ -0,0 +1,101 @@
+use super::Decoder;
+use crate::{Leaf, LeafData};
+
+use embedded_graphics::{
+    image::{Image, ImageDrawable, ImageRaw},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::Rectangle,
+};
+
+impl Dimensions for Leaf {
+    fn bounding_box(&self) -> Rectangle {
+        let (x, y) = self.origin();
+        let point = Point::new(x as i32, y as i32);
+        let size = Size::new_equal(self.side());
+
+        Rectangle::new(point, size)
+    }
+}
+
+impl Drawable for Leaf {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<DT>(&self, target: &mut DT) -> Result<Self::Output, DT::Error>
+    where
+        DT: DrawTarget<Color = Self::Color>,
+    {
+        match self.data {
+            LeafData::Feature(f) => target.fill_solid(&self.bounding_box(), f.into()),
+            LeafData::Bitmap(b) => {
+                let data = [b[0] & 0xf0, b[0] << 4, b[1] & 0xf0, b[1] << 4];
+                let raw = ImageRaw::<BinaryColor>::new(&data, 4);
+                let image = Image::new(&raw, self.bounding_box().top_left);
+                image.draw(target)
+            }
+        }
+    }
+}
+
+impl Leaf {
+    fn draw_sub_image<DT>(&self, target: &mut DT, area: &Rectangle) -> Result<(), DT::Error>
+    where
+        DT: DrawTarget<Color = <Self as Drawable>::Color>,
+    {
+        match self.data {
+            LeafData::Feature(f) => target.fill_solid(area, f.into()),
+            LeafData::Bitmap(b) => {
+                let data = [b[0] & 0xf0, b[0] << 4, b[1] & 0xf0, b[1] << 4];
+                let raw = ImageRaw::<BinaryColor>::new(&data, 4);
+                raw.draw_sub_image(target, area)
+            }
+        }
+    }
+}
+
+pub struct DrawWrapper<D>(pub(super) D);
+
+impl<'a, D: Decoder<'a>> OriginDimensions for DrawWrapper<D> {
+    fn size(&self) -> Size {
+        let res = self.0.resolution();
+        Size::new(res.width() as u32, res.height() as u32)
+    }
+}
+
+impl<'a, D: Decoder<'a>> ImageDrawable for DrawWrapper<D> {
+    type Color = BinaryColor;
+
+    fn draw<DT>(&self, target: &mut DT) -> Result<(), DT::Error>
+    where
+        DT: DrawTarget<Color = Self::Color>,
+    {
+        // leaves can cover the padding around the image
+        let mut target = target.clipped(&self.bounding_box());
+
+        if let Some(c) = self.0.clear_framebuffer() {
+            target.clear(c.into())?;
+        }
+
+        for leaf in self.0.iter().map_while(Result::ok) {
+            leaf.draw(&mut target)?
+        }
+
+        Ok(())
+    }
+
+    fn draw_sub_image<DT>(&self, target: &mut DT, area: &Rectangle) -> Result<(), DT::Error>
+    where
+        DT: DrawTarget<Color = Self::Color>,
+    {
+        let area = area.intersection(&self.bounding_box());
+        for leaf in self.0.iter().map_while(Result::ok) {
+            let rect = leaf.bounding_box().intersection(&area);
+
+            if !rect.is_zero_sized() {
+                leaf.draw_sub_image(target, &rect)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use super::{
+    raw::{Framebuffer, Layout},
+    video::VideoSlice,
+    Decoder, ParseError, ParseFrame,
+};
+use crate::Resolution;
+
+use image::{
+    codecs::{
+        gif::{GifEncoder, Repeat},
+        png::PngEncoder,
+    },
+    ColorType, Delay, DynamicImage, Frame, GrayImage, ImageError,
+};
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+/// Error returned when exporting decoded frames.
+#[derive(Debug)]
+pub enum ExportError {
+    Parse(ParseError),
+    Image(ImageError),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "can't decode the frame: {}", e),
+            Self::Image(e) => write!(f, "can't write the image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<ParseError> for ExportError {
+    fn from(e: ParseError) -> Self {
+        Self::Parse(e)
+    }
+}
+
+impl From<ImageError> for ExportError {
+    fn from(e: ImageError) -> Self {
+        Self::Image(e)
+    }
+}
+
+/// Grayscale image of a row-major buffer, lit pixels are white.
+pub fn to_image(buf: &[u8], resolution: Resolution) -> GrayImage {
+    let (width, height) = (resolution.width() as u32, resolution.height() as u32);
+    GrayImage::from_fn(width, height, |x, y| {
+        let byte = buf[y as usize * resolution.stride() + x as usize / 8];
+        [if byte << (x % 8) & 0x80 != 0 { 0xff } else { 0 }].into()
+    })
+}
+
+/// Decodes a single frame into a row-major buffer, starting from unlit pixels.
+pub fn render<'a, D: Decoder<'a>>(dec: &D) -> Result<Vec<u8>, ParseError> {
+    let resolution = dec.resolution();
+    let mut buf = vec![0; resolution.buf_len()];
+    Framebuffer::new(&mut buf, resolution, Layout::RowMajor)
+        .unwrap()
+        .draw(dec)?;
+    Ok(buf)
+}
+
+/// Decodes a stream into the row-major buffer of every displayed frame.
+///
+/// Stops after the first invalid frame.
+pub struct Rendered<'a, D: ParseFrame<'a>> {
+    frames: VideoSlice<'a, D>,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<'a, D: ParseFrame<'a>> Rendered<'a, D> {
+    pub fn new(frames: VideoSlice<'a, D>) -> Self {
+        Self {
+            buf: vec![0; frames.resolution().buf_len()],
+            frames,
+            done: false,
+        }
+    }
+
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>, ParseError> {
+        let resolution = self.frames.resolution();
+        let mut fb = Framebuffer::new(&mut self.buf, resolution, Layout::RowMajor).unwrap();
+        for dec in &mut self.frames {
+            let dec = dec?;
+            fb.draw(&dec)?;
+            if dec.flush_after() {
+                return Ok(Some(fb.as_bytes().to_vec()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, D: ParseFrame<'a>> Iterator for Rendered<'a, D> {
+    type Item = Result<Vec<u8>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let res = self.next_frame().transpose();
+        self.done = !matches!(res, Some(Ok(_)));
+        res
+    }
+}
+
+/// Writes a binary PBM image, which has the same row layout as the buffer.
+pub fn write_pbm(buf: &[u8], resolution: Resolution, mut w: impl Write) -> io::Result<()> {
+    // PBM pixels are black when set, lit ones are white
+    let inverted: Vec<u8> = buf.iter().map(|b| !b).collect();
+    write!(w, "P4\n{} {}\n", resolution.width(), resolution.height())?;
+    w.write_all(&inverted)
+}
+
+pub fn write_png(buf: &[u8], resolution: Resolution, w: impl Write) -> Result<(), ImageError> {
+    let image = to_image(buf, resolution);
+    PngEncoder::new(w).encode(&image, image.width(), image.height(), ColorType::L8)
+}
+
+/// Writes the displayed frames of a stream as a looping animated GIF.
+///
+/// GIF delays are counted in hundredths of a second, they're rounded so that the total length
+/// stays right, e.g. 30 fps alternates between delays of 30 and 40ms.
+pub fn write_gif<'a, D: ParseFrame<'a>>(
+    frames: VideoSlice<'a, D>,
+    frame_rate: u16,
+    w: impl Write,
+) -> Result<(), ExportError> {
+    let resolution = frames.resolution();
+    let frame_rate = frame_rate.max(1) as u64;
+    let mut enc = GifEncoder::new(w);
+    enc.set_repeat(Repeat::Infinite)?;
+
+    for (i, buf) in Rendered::new(frames).enumerate() {
+        let i = i as u64;
+        let centis = (i + 1) * 100 / frame_rate - i * 100 / frame_rate;
+        let image = DynamicImage::ImageLuma8(to_image(&buf?, resolution)).into_rgba8();
+        let delay = Delay::from_numer_denom_ms(centis as u32 * 10, 1);
+        enc.encode_frame(Frame::from_parts(image, 0, 0, delay))?;
+    }
+    Ok(())
+}
@@ -1,105 +1,159 @@
-use crate::{FrameMeta, Leaf, LeafData, utils::next_pos};
+use crate::{
+    FrameMeta, Leaf, LeafData, Resolution, TreeFormat,
+    entropy::{NodeModel, RangeDecoder},
+    utils::next_pos,
+};
 
 use bitvec::prelude::*;
 use core::{
-    cmp,
     convert::{TryFrom, TryInto},
-};
-use embedded_graphics::{
-    image::{Image, ImageDrawable, ImageRaw},
-    pixelcolor::BinaryColor,
-    prelude::*,
-    primitives::Rectangle,
+    fmt,
 };
 
+#[cfg(feature = "graphics")]
+mod draw;
+#[cfg(all(feature = "std", feature = "image"))]
+pub mod export;
+mod motion;
+pub mod raw;
+pub mod stream;
 pub mod video;
 
+#[cfg(feature = "graphics")]
+pub use draw::DrawWrapper;
+pub use motion::{BlockCopy, Copies, FrameHeader, Motion, Shift};
+
 #[cfg(test)]
 mod tests;
 
-impl Dimensions for Leaf {
-    fn bounding_box(&self) -> Rectangle {
-        let es = 1 << (7 - self.depth());
-        let mut x = 0;
-        let mut y = 0;
-
-        for (i, p) in self.pos.iter().enumerate() {
-            x |= (p & 1) << 6 - i;
-            y |= (p >> 1) << 6 - i;
-        }
-        let point = Point::new(x as i32, y as i32);
-        let size = Size::new_equal(es);
+pub trait Decoder<'a>: Sized {
+    type Iterator: Iterator<Item = Result<Leaf, ParseError>>;
+    fn resolution(&self) -> Resolution;
+    fn iter(&self) -> Self::Iterator;
+    fn flush_after(&self) -> bool;
+    /// Value to fill the framebuffer with before drawing the leaves, if any.
+    fn clear_framebuffer(&self) -> Option<bool>;
+    /// Offset to move the previous frame by before drawing the leaves, if any, see `Motion`.
+    ///
+    /// Targets drawn through embedded-graphics can't be read back, so only
+    /// `raw::Framebuffer` applies it and the copies.
+    fn shift(&self) -> Option<Shift> {
+        None
+    }
+    /// Squares of the previous frame to copy after the shift, see `BlockCopy`.
+    fn copies(&self) -> Copies<'a> {
+        Copies::default()
+    }
+    /// Whether the lit pixels of the leaves toggle the framebuffer instead of being drawn,
+    /// see `FrameMeta::xor`.
+    ///
+    /// Like the shift, only `raw::Framebuffer` applies it.
+    fn xor(&self) -> bool {
+        false
+    }
+    /// Drawing stops at the first invalid leaf, use `validate` to check the frame beforehand.
+    #[cfg(feature = "graphics")]
+    fn drawable(self) -> DrawWrapper<Self> {
+        DrawWrapper(self)
+    }
+    /// Parses the whole frame, returning the first error found.
+    fn validate(&self) -> Result<(), ParseError> {
+        self.iter().try_for_each(|leaf| leaf.map(drop))
+    }
+}
 
-        Rectangle::new(point, size)
+/// Decoders of a single tree format, which can parse a bare frame.
+pub trait FromBuf<'a>: Decoder<'a> {
+    /// Parses a 128x64 frame.
+    fn from_buf(buf: &'a [u8]) -> Result<Self, ParseError> {
+        Self::with_resolution(buf, Resolution::default())
     }
+    /// Parses a frame of any resolution, the tree depth is derived from it.
+    fn with_resolution(buf: &'a [u8], resolution: Resolution) -> Result<Self, ParseError>;
 }
 
-impl Drawable for Leaf {
-    type Color = BinaryColor;
-    type Output = ();
+/// Decoders `VideoSlice` parses the frames of a stream with.
+pub trait ParseFrame<'a>: Decoder<'a> {
+    /// What a frame doesn't record about itself, besides the resolution
+    type Format: Copy + fmt::Debug;
+    fn parse_frame(
+        buf: &'a [u8],
+        format: Self::Format,
+        resolution: Resolution,
+    ) -> Result<Self, ParseError>;
+}
 
-    fn draw<DT>(&self, target: &mut DT) -> Result<Self::Output, DT::Error>
-    where
-        DT: DrawTarget<Color = Self::Color>,
-    {
-        match self.data {
-            LeafData::Feature(f) => target.fill_solid(&self.bounding_box(), f.into()),
-            LeafData::Bitmap(b) => {
-                let data = [b[0] & 0xf0, b[0] << 4, b[1] & 0xf0, b[1] << 4];
-                let raw = ImageRaw::<BinaryColor>::new(&data, 4);
-                let image = Image::new(&raw, self.bounding_box().top_left);
-                image.draw(target)
-            }
-        }
+impl<'a, D: FromBuf<'a>> ParseFrame<'a> for D {
+    type Format = ();
+    fn parse_frame(buf: &'a [u8], _: (), resolution: Resolution) -> Result<Self, ParseError> {
+        Self::with_resolution(buf, resolution)
     }
 }
 
-impl Leaf {
-    fn draw_sub_image<DT>(&self, target: &mut DT, area: &Rectangle) -> Result<(), DT::Error>
-    where
-        DT: DrawTarget<Color = <Self as Drawable>::Color>,
-    {
-        match self.data {
-            LeafData::Feature(f) => target.fill_solid(area, f.into()),
-            LeafData::Bitmap(b) => {
-                let data = [b[0] & 0xf0, b[0] << 4, b[1] & 0xf0, b[1] << 4];
-                let raw = ImageRaw::<BinaryColor>::new(&data, 4);
-                raw.draw_sub_image(target, area)
-            }
-        }
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ParseError {
+    InvalidHeader,
+    /// Unknown container version or tree format
+    UnsupportedFormat,
+    /// The data ends in the middle of a leaf, a frame or before the tree is complete
+    Truncated,
+    /// A leaf is deeper than the tree, or its depth can't be represented in the format
+    InvalidDepth,
+    /// A branch would split single pixels
+    TreeOverflow,
+    /// Data follows the end of the tree
+    TrailingData,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidHeader => "invalid header",
+            Self::UnsupportedFormat => "unsupported format",
+            Self::Truncated => "truncated data",
+            Self::InvalidDepth => "invalid leaf depth",
+            Self::TreeOverflow => "tree deeper than the image",
+            Self::TrailingData => "trailing data after the tree",
+        })
     }
 }
 
-pub struct DrawWrapper<D>(D);
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
 
-pub trait Decoder<'a>: Sized {
-    type Iterator: Iterator<Item = Leaf>;
-    fn from_buf(buf: &'a [u8]) -> Result<Self, ParseError>;
-    fn iter(&self) -> Self::Iterator;
-    fn flush_after(&self) -> bool;
-    fn clear_framebuffer(&self) -> Option<BinaryColor>;
-    fn drawable(self) -> DrawWrapper<Self> {
-        DrawWrapper(self)
-    }
+/// Error returned when drawing a frame after parsing it.
+#[derive(Debug, PartialEq)]
+pub enum DrawError<E> {
+    Parse(ParseError),
+    Draw(E),
 }
 
-#[derive(Debug)]
-pub enum ParseError {
-    InvalidHeader,
+impl<E> From<ParseError> for DrawError<E> {
+    fn from(e: ParseError) -> Self {
+        Self::Parse(e)
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct LeafParserV1<'a> {
     buf: &'a [u8],
     meta: FrameMeta,
+    resolution: Resolution,
 }
 
 impl<'a> LeafParserV1<'a> {
     pub fn new(buf: &'a [u8]) -> Result<Self, ParseError> {
-        match buf.get(0).map(|meta| FrameMeta::try_from(*meta)) {
+        Self::with_resolution(buf, Resolution::default())
+    }
+}
+
+impl<'a> FromBuf<'a> for LeafParserV1<'a> {
+    fn with_resolution(buf: &'a [u8], resolution: Resolution) -> Result<Self, ParseError> {
+        match buf.first().map(|meta| FrameMeta::try_from(*meta)) {
             Some(Ok(meta)) => Ok(Self {
                 buf: &buf[1..],
                 meta,
+                resolution,
             }),
             _ => Err(ParseError::InvalidHeader),
         }
@@ -108,183 +162,451 @@ impl<'a> LeafParserV1<'a> {
 
 impl<'a> Decoder<'a> for LeafParserV1<'a> {
     type Iterator = LeafParserIterV1<'a>;
-    fn from_buf(buf: &'a [u8]) -> Result<Self, ParseError> {
-        Self::new(buf)
+    fn resolution(&self) -> Resolution {
+        self.resolution
     }
     fn iter(&self) -> LeafParserIterV1<'a> {
         LeafParserIterV1 {
             buf: self.buf,
             index: 0,
-            feature: self.meta.active_feature,
+            // toggled pixels are the lit ones
+            feature: self.meta.active_feature || self.meta.xor,
+            root: self.resolution.depth(),
         }
     }
     fn flush_after(&self) -> bool {
         self.meta.display
     }
-    fn clear_framebuffer(&self) -> Option<BinaryColor> {
+    fn clear_framebuffer(&self) -> Option<bool> {
         if !self.meta.partial {
-            Some(BinaryColor::from(!self.meta.active_feature))
+            Some(!self.meta.active_feature)
         } else {
             None
         }
     }
+    fn xor(&self) -> bool {
+        self.meta.xor
+    }
 }
 
 pub struct LeafParserIterV1<'a> {
     buf: &'a [u8],
     index: usize,
     feature: bool,
+    root: u8,
 }
 
-impl<'a> Iterator for LeafParserIterV1<'a> {
-    type Item = Leaf;
+impl<'a> LeafParserIterV1<'a> {
+    fn parse_leaf(&mut self) -> Result<Leaf, ParseError> {
+        let head = self.buf[self.index].view_bits::<Msb0>();
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.buf.len() < self.index + 1 {
-            return None;
+        // positions start after the discriminant and depth bits
+        let (depth, bitmap, first_pos) = match (head[0], head[1..=3].load::<u8>()) {
+            (false, _) => (7, false, 2),
+            (true, 6) if (2..=7).contains(&self.root) => (self.root - 2, true, 4),
+            (true, d) if d < 6 => (d, false, 4),
+            _ => return Err(ParseError::InvalidDepth),
+        };
+        if depth > self.root {
+            return Err(ParseError::InvalidDepth);
+        }
+
+        let len = if first_pos + depth as usize * 2 > 8 {
+            2
+        } else {
+            1
+        };
+        let bytes = self
+            .buf
+            .get(self.index..self.index + len)
+            .ok_or(ParseError::Truncated)?;
+        self.index += len;
+
+        let bits = &bytes.view_bits::<Msb0>()[first_pos..];
+        let pos = bits
+            .chunks_exact(2)
+            .take(depth as usize)
+            .map(|side| side.load::<u8>())
+            .collect();
+
+        let data = if bitmap {
+            let b = self
+                .buf
+                .get(self.index..self.index + 2)
+                .ok_or(ParseError::Truncated)?;
+            self.index += 2;
+            LeafData::Bitmap(b.try_into().unwrap())
+        } else {
+            LeafData::Feature(self.feature)
         };
 
-        let byte = self.buf[self.index];
-        let cur = byte.view_bits::<Msb0>();
+        Ok(Leaf::new(data, pos, self.root))
+    }
+}
 
-        let mut pos = heapless::Vec::new();
+impl<'a> Iterator for LeafParserIterV1<'a> {
+    type Item = Result<Leaf, ParseError>;
 
-        let (depth, base_index) = if cur[0] {
-            let depth: u8 = cur[1..=3].load();
-            (depth, 1)
-        } else {
-            (7, 0)
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.buf.len() {
+            return None;
         };
 
-        for i in base_index..3 {
-            let bitpos = (i + 1) * 2;
-            let side = cur[bitpos..=bitpos + 1].load();
-            pos.push(side).expect("Exceeded max depth");
+        let leaf = self.parse_leaf();
+        if leaf.is_err() {
+            // the rest of the stream can't be trusted
+            self.index = self.buf.len();
         }
+        Some(leaf)
+    }
+}
 
-        pos.truncate(depth as usize);
-
-        if depth > 2 {
-            self.index += 1;
-            let next = self.buf.get(self.index);
-            let next = if let Some(b) = next {
-                b.view_bits::<Msb0>()
-            } else {
-                return None;
-            };
-
-            for i in 3..=cmp::min(depth, 5) as usize {
-                let bitpos = (i - 3) * 2;
-                let side = next[bitpos..=bitpos + 1].load();
-                pos.push(side).expect("Exceeded max depth");
-            }
-        }
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeafParserV2<'a> {
+    buf: &'a [u8],
+    resolution: Resolution,
+}
 
-        self.index += 1;
+impl<'a> FromBuf<'a> for LeafParserV2<'a> {
+    fn with_resolution(buf: &'a [u8], resolution: Resolution) -> Result<Self, ParseError> {
+        Ok(Self { buf, resolution })
+    }
+}
 
-        let data;
-        if depth < 6 {
-            data = LeafData::Feature(self.feature)
-        } else {
-            if self.buf.len() < self.index + 2 {
-                return None;
-            }
-            data = LeafData::Bitmap(self.buf[self.index..self.index + 2].try_into().unwrap());
-            self.index += 2;
-        };
+impl<'a> Decoder<'a> for LeafParserV2<'a> {
+    type Iterator = LeafParserIterV2<'a>;
+    fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+    fn iter(&self) -> Self::Iterator {
+        LeafParserIterV2::new(self.buf, self.resolution, false)
+    }
+    fn flush_after(&self) -> bool {
+        true
+    }
+    fn clear_framebuffer(&self) -> Option<bool> {
+        None
+    }
+}
+
+/// Compact tree format with bitmap leaves, see `QuadTree::collect_compact_bitmap`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeafParserV3<'a> {
+    buf: &'a [u8],
+    resolution: Resolution,
+}
 
-        Some(Self::Item { pos, data })
+impl<'a> FromBuf<'a> for LeafParserV3<'a> {
+    fn with_resolution(buf: &'a [u8], resolution: Resolution) -> Result<Self, ParseError> {
+        Ok(Self { buf, resolution })
     }
 }
 
-impl<'a, D: Decoder<'a>> OriginDimensions for DrawWrapper<D> {
-    fn size(&self) -> Size {
-        Size::new_equal(2u32.pow(7))
+impl<'a> Decoder<'a> for LeafParserV3<'a> {
+    type Iterator = LeafParserIterV2<'a>;
+    fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+    fn iter(&self) -> Self::Iterator {
+        LeafParserIterV2::new(self.buf, self.resolution, true)
+    }
+    fn flush_after(&self) -> bool {
+        true
+    }
+    fn clear_framebuffer(&self) -> Option<bool> {
+        None
     }
 }
 
-impl<'a, D: Decoder<'a>> ImageDrawable for DrawWrapper<D> {
-    type Color = BinaryColor;
+/// Iterator over the leaves of a compact tree, shared by the V2 and V3 formats.
+pub struct LeafParserIterV2<'a> {
+    bits: &'a BitSlice<u8, Msb0>,
+    index: usize,
+    pos: crate::Position,
+    root: u8,
+    state: TreeState,
+    /// Whether branches of 4x4 pixels are followed by the bitmap escape bit
+    bitmaps: bool,
+}
 
-    fn draw<DT>(&self, target: &mut DT) -> Result<(), DT::Error>
-    where
-        DT: DrawTarget<Color = Self::Color>,
-    {
-        if let Some(c) = self.0.clear_framebuffer() {
-            target.clear(c)?;
-        }
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TreeState {
+    Parsing,
+    /// The root node is complete, only padding is left
+    Complete,
+    /// Done with the stream, because of an error or the end of the tree
+    Finished,
+}
 
-        for leaf in self.0.iter() {
-            leaf.draw(target)?
+impl<'a> LeafParserIterV2<'a> {
+    fn new(buf: &'a [u8], resolution: Resolution, bitmaps: bool) -> Self {
+        Self {
+            bits: buf.view_bits(),
+            index: 0,
+            pos: Default::default(),
+            root: resolution.depth(),
+            state: TreeState::Parsing,
+            bitmaps,
         }
+    }
 
-        Ok(())
+    fn take(&mut self, len: usize) -> Result<&'a BitSlice<u8, Msb0>, ParseError> {
+        let bits = self
+            .bits
+            .get(self.index..self.index + len)
+            .ok_or(ParseError::Truncated)?;
+        self.index += len;
+        Ok(bits)
     }
 
-    fn draw_sub_image<DT>(&self, target: &mut DT, area: &Rectangle) -> Result<(), DT::Error>
-    where
-        DT: DrawTarget<Color = Self::Color>,
-    {
-        for leaf in self.0.iter() {
-            let rect = leaf.bounding_box().intersection(area);
+    fn parse_node(&mut self) -> Result<Option<Leaf>, ParseError> {
+        let leaf = match self.take(2)?.load_be::<u8>() {
+            0b00 => None, // empty node
+            0b01 if self.bitmaps
+                && self.pos.len() + 2 == self.root as usize
+                && self.take(1)?[0] =>
+            {
+                // 4x4 bitmap leaf
+                let bitmap = self.take(16)?;
+                Some(Leaf::new(
+                    LeafData::Bitmap([bitmap[..8].load_be(), bitmap[8..].load_be()]),
+                    self.pos.clone(),
+                    self.root,
+                ))
+            }
+            0b01 => {
+                // branch
+                if self.pos.len() >= self.root as usize {
+                    return Err(ParseError::TreeOverflow);
+                }
+                self.pos.push(0).unwrap();
+                return Ok(None);
+            }
+            // leaf, value is the second bit
+            v => Some(Leaf::new(
+                LeafData::Feature(v == 0b11),
+                self.pos.clone(),
+                self.root,
+            )),
+        };
+
+        next_pos(&mut self.pos);
+        if self.pos.is_empty() {
+            self.state = TreeState::Complete;
+        }
+        Ok(leaf)
+    }
+}
 
-            if !rect.is_zero_sized() {
-                leaf.draw_sub_image(target, &rect)?;
+impl Iterator for LeafParserIterV2<'_> {
+    type Item = Result<Leaf, ParseError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.state {
+                TreeState::Parsing => match self.parse_node() {
+                    Ok(Some(leaf)) => return Some(Ok(leaf)),
+                    Ok(None) => (),
+                    Err(e) => {
+                        self.state = TreeState::Finished;
+                        return Some(Err(e));
+                    }
+                },
+                TreeState::Complete => {
+                    self.state = TreeState::Finished;
+                    // the last byte is padded with zeroes
+                    let rest = &self.bits[self.index..];
+                    if rest.len() >= 8 || rest.any() {
+                        return Some(Err(ParseError::TrailingData));
+                    }
+                }
+                TreeState::Finished => return None,
             }
         }
-        Ok(())
     }
 }
 
+/// Range coded compact tree format, see `QuadTree::collect_compact_entropy`.
 #[derive(Debug, Clone, PartialEq)]
-pub struct LeafParserV2<'a> {
-    buf: &'a [u8]
+pub struct LeafParserV4<'a> {
+    buf: &'a [u8],
+    resolution: Resolution,
 }
 
-impl<'a> Decoder<'a> for LeafParserV2<'a> {
-    type Iterator = LeafParserIterV2<'a>;
-    fn from_buf(buf: &'a [u8]) -> Result<Self, ParseError> {
-        Ok(Self{ buf })
+impl<'a> FromBuf<'a> for LeafParserV4<'a> {
+    fn with_resolution(buf: &'a [u8], resolution: Resolution) -> Result<Self, ParseError> {
+        Ok(Self { buf, resolution })
+    }
+}
+
+impl<'a> Decoder<'a> for LeafParserV4<'a> {
+    type Iterator = LeafParserIterV4<'a>;
+    fn resolution(&self) -> Resolution {
+        self.resolution
     }
     fn iter(&self) -> Self::Iterator {
-        LeafParserIterV2 {
-            inner: self.buf.view_bits().chunks_exact(2),
+        LeafParserIterV4 {
+            rc: RangeDecoder::new(self.buf),
+            model: Default::default(),
             pos: Default::default(),
+            root: self.resolution.depth(),
+            state: TreeState::Parsing,
         }
     }
     fn flush_after(&self) -> bool {
         true
     }
-    fn clear_framebuffer(&self) -> Option<BinaryColor> {
+    fn clear_framebuffer(&self) -> Option<bool> {
         None
     }
 }
 
-pub struct LeafParserIterV2<'a> {
-    inner: bitvec::slice::ChunksExact<'a, u8, Msb0>,
+pub struct LeafParserIterV4<'a> {
+    rc: RangeDecoder<'a>,
+    model: NodeModel,
     pos: crate::Position,
+    root: u8,
+    state: TreeState,
 }
 
-impl Iterator for LeafParserIterV2<'_> {
-    type Item = Leaf;
+impl LeafParserIterV4<'_> {
+    fn parse_node(&mut self) -> Result<Option<Leaf>, ParseError> {
+        let leaf = match self.model.decode(&mut self.rc, &self.pos)? {
+            0b00 => None, // empty node
+            0b01 => {
+                // branch
+                if self.pos.len() >= self.root as usize {
+                    return Err(ParseError::TreeOverflow);
+                }
+                self.pos.push(0).unwrap();
+                return Ok(None);
+            }
+            // leaf, value is the second bit
+            v => Some(Leaf::new(
+                LeafData::Feature(v == 0b11),
+                self.pos.clone(),
+                self.root,
+            )),
+        };
+
+        next_pos(&mut self.pos);
+        if self.pos.is_empty() {
+            self.state = TreeState::Complete;
+        }
+        Ok(leaf)
+    }
+}
+
+impl Iterator for LeafParserIterV4<'_> {
+    type Item = Result<Leaf, ParseError>;
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(bits) = self.inner.next() {
-            match bits.load::<u8>() {
-                0b00 => next_pos(&mut self.pos)?, // empty node
-                0b01 => self.pos.push(0).expect("Max depth exceeded"), // branch
-                0b10 => { // leaf, value = 0
-                    let pos = self.pos.clone();
-                    next_pos(&mut self.pos)?;
-                    return Some(Leaf::new(LeafData::Feature(false), pos))
+        loop {
+            match self.state {
+                TreeState::Parsing => match self.parse_node() {
+                    Ok(Some(leaf)) => return Some(Ok(leaf)),
+                    Ok(None) => (),
+                    Err(e) => {
+                        self.state = TreeState::Finished;
+                        return Some(Err(e));
+                    }
                 },
-                0b11 => { // leaf, value = 1
-                    let pos = self.pos.clone();
-                    next_pos(&mut self.pos)?;
-                    return Some(Leaf::new(LeafData::Feature(true), pos))
+                TreeState::Complete => {
+                    self.state = TreeState::Finished;
+                    // the range coder is flushed exactly at the end of the tree
+                    if self.rc.remaining() > 0 {
+                        return Some(Err(ParseError::TrailingData));
+                    }
                 }
-                _ => unreachable!(),
+                TreeState::Finished => return None,
             }
         }
-        None
+    }
+}
+
+/// Decoder for any of the supported tree formats, picked at runtime.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnyDecoder<'a> {
+    V1(LeafParserV1<'a>),
+    V2(LeafParserV2<'a>),
+    V3(LeafParserV3<'a>),
+    V4(LeafParserV4<'a>),
+}
+
+impl<'a> AnyDecoder<'a> {
+    pub fn new(
+        buf: &'a [u8],
+        format: TreeFormat,
+        resolution: Resolution,
+    ) -> Result<Self, ParseError> {
+        Ok(match format {
+            TreeFormat::Packed => Self::V1(LeafParserV1::with_resolution(buf, resolution)?),
+            TreeFormat::Compact => Self::V2(LeafParserV2::with_resolution(buf, resolution)?),
+            TreeFormat::CompactBitmap => Self::V3(LeafParserV3::with_resolution(buf, resolution)?),
+            TreeFormat::CompactEntropy => Self::V4(LeafParserV4::with_resolution(buf, resolution)?),
+        })
+    }
+}
+
+/// A bare frame doesn't record its format, so `AnyDecoder` doesn't implement `FromBuf`:
+/// frames are parsed with `AnyDecoder::new`, or by `Video::frames` from the format in the header.
+impl<'a> Decoder<'a> for AnyDecoder<'a> {
+    type Iterator = AnyLeafIter<'a>;
+    fn resolution(&self) -> Resolution {
+        match self {
+            Self::V1(d) => d.resolution(),
+            Self::V2(d) => d.resolution(),
+            Self::V3(d) => d.resolution(),
+            Self::V4(d) => d.resolution(),
+        }
+    }
+    fn iter(&self) -> Self::Iterator {
+        match self {
+            Self::V1(d) => AnyLeafIter::V1(d.iter()),
+            Self::V2(d) => AnyLeafIter::V2(d.iter()),
+            Self::V3(d) => AnyLeafIter::V2(d.iter()),
+            Self::V4(d) => AnyLeafIter::V4(d.iter()),
+        }
+    }
+    fn flush_after(&self) -> bool {
+        match self {
+            Self::V1(d) => d.flush_after(),
+            Self::V2(d) => d.flush_after(),
+            Self::V3(d) => d.flush_after(),
+            Self::V4(d) => d.flush_after(),
+        }
+    }
+    fn clear_framebuffer(&self) -> Option<bool> {
+        match self {
+            Self::V1(d) => d.clear_framebuffer(),
+            Self::V2(d) => d.clear_framebuffer(),
+            Self::V3(d) => d.clear_framebuffer(),
+            Self::V4(d) => d.clear_framebuffer(),
+        }
+    }
+    fn xor(&self) -> bool {
+        match self {
+            Self::V1(d) => d.xor(),
+            Self::V2(d) => d.xor(),
+            Self::V3(d) => d.xor(),
+            Self::V4(d) => d.xor(),
+        }
+    }
+}
+
+// boxing the range coder state would need an allocator
+#[allow(clippy::large_enum_variant)]
+pub enum AnyLeafIter<'a> {
+    V1(LeafParserIterV1<'a>),
+    V2(LeafParserIterV2<'a>),
+    V4(LeafParserIterV4<'a>),
+}
+
+impl Iterator for AnyLeafIter<'_> {
+    type Item = Result<Leaf, ParseError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::V1(i) => i.next(),
+            Self::V2(i) => i.next(),
+            Self::V4(i) => i.next(),
+        }
     }
 }
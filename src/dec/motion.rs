@@ -0,0 +1,176 @@
+use super::{Decoder, FromBuf, ParseError};
+use crate::Resolution;
+
+/// `(dx, dy)` the previous frame moves by, right and down when positive
+pub type Shift = (i16, i16);
+
+/// Frame of a stream with frame headers, which can move the previous frame or copy squares of
+/// it before the leaves of `D` are drawn over it.
+///
+/// The header starts with a flags byte, `0` for plain frames.
+/// Bit 0 is followed by the shift of the whole frame as two little endian `i16`, `dx` then `dy`.
+/// Bit 1 is followed by a `u16` count of block copies, see `BlockCopy`.
+/// The previous frame moves right and down by positive shifts, the uncovered pixels are unlit.
+/// Scrolling and panning frames are sent as a shift and the leaves that still differ,
+/// moving sprites as copies of their squares.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Motion<'a, D> {
+    inner: D,
+    header: FrameHeader<'a>,
+}
+
+/// Moves applied to the previous frame before the leaves are drawn, see `Motion`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FrameHeader<'a> {
+    pub shift: Option<Shift>,
+    pub copies: Copies<'a>,
+}
+
+impl<'a> FrameHeader<'a> {
+    const SHIFT: u8 = 1;
+    const COPIES: u8 = 2;
+
+    /// Splits the frame header from the tree.
+    pub fn parse(buf: &'a [u8]) -> Result<(Self, &'a [u8]), ParseError> {
+        let (&flags, mut rest) = buf.split_first().ok_or(ParseError::Truncated)?;
+        if flags & !(Self::SHIFT | Self::COPIES) != 0 {
+            return Err(ParseError::InvalidHeader);
+        }
+        let mut take = |len: usize| {
+            let taken = rest.get(..len).ok_or(ParseError::Truncated)?;
+            rest = &rest[len..];
+            Ok(taken)
+        };
+
+        let mut header = Self::default();
+        if flags & Self::SHIFT != 0 {
+            let b = take(4)?;
+            header.shift = Some((
+                i16::from_le_bytes([b[0], b[1]]),
+                i16::from_le_bytes([b[2], b[3]]),
+            ));
+        }
+        if flags & Self::COPIES != 0 {
+            let count = take(2)?;
+            let count = u16::from_le_bytes([count[0], count[1]]) as usize;
+            let buf = take(count * BlockCopy::LEN)?;
+            if buf
+                .chunks(BlockCopy::LEN)
+                .any(|c| BlockCopy::parse(c).is_none())
+            {
+                return Err(ParseError::InvalidHeader);
+            }
+            header.copies = Copies { buf };
+        }
+        Ok((header, rest))
+    }
+
+    /// Whether the frame is drawn over the previous one as it is
+    pub fn is_plain(&self) -> bool {
+        self.shift.is_none() && self.copies.buf.is_empty()
+    }
+}
+
+/// Copy of a square of the previous frame, moved by `(dx, dy)`.
+///
+/// The squares are the ones of the quadtree, their side is a power of two and they're aligned
+/// to it, so `x` and `y` are multiples of `side`.
+/// Stored in 9 bytes: `x: u16`, `y: u16`, the base 2 logarithm of `side` as `u8`,
+/// then `dx: i16` and `dy: i16`.
+///
+/// Copies are applied in order to the frame being drawn, after the shift: a copy reads the
+/// pixels left by the previous copies, which the encoder accounts for.
+/// Pixels read from outside of the image are unlit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockCopy {
+    /// Left edge of the square in the new frame
+    pub x: u16,
+    /// Top edge of the square in the new frame
+    pub y: u16,
+    pub side: u16,
+    pub dx: i16,
+    pub dy: i16,
+}
+
+impl BlockCopy {
+    pub const LEN: usize = 9;
+
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let side = 1u16.checked_shl(buf[4] as u32)?;
+        let copy = Self {
+            x: u16::from_le_bytes([buf[0], buf[1]]),
+            y: u16::from_le_bytes([buf[2], buf[3]]),
+            side,
+            dx: i16::from_le_bytes([buf[5], buf[6]]),
+            dy: i16::from_le_bytes([buf[7], buf[8]]),
+        };
+        ((copy.x | copy.y) & (side - 1) == 0).then_some(copy)
+    }
+}
+
+/// Iterator over the block copies of a frame header, checked when the header was parsed
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Copies<'a> {
+    buf: &'a [u8],
+}
+
+impl Iterator for Copies<'_> {
+    type Item = BlockCopy;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (copy, rest) = (self.buf.get(..BlockCopy::LEN)?, &self.buf[BlockCopy::LEN..]);
+        self.buf = rest;
+        BlockCopy::parse(copy)
+    }
+}
+
+impl<'a, D> Motion<'a, D> {
+    /// Wraps a frame without a header, it doesn't change the previous frame.
+    pub fn plain(inner: D) -> Self {
+        Self::new(inner, FrameHeader::default())
+    }
+
+    pub fn new(inner: D, header: FrameHeader<'a>) -> Self {
+        Self { inner, header }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn header(&self) -> &FrameHeader<'a> {
+        &self.header
+    }
+}
+
+impl<'a, D: FromBuf<'a>> FromBuf<'a> for Motion<'a, D> {
+    fn with_resolution(buf: &'a [u8], resolution: Resolution) -> Result<Self, ParseError> {
+        let (header, tree) = FrameHeader::parse(buf)?;
+        Ok(Self::new(D::with_resolution(tree, resolution)?, header))
+    }
+}
+
+impl<'a, D: Decoder<'a>> Decoder<'a> for Motion<'a, D> {
+    type Iterator = D::Iterator;
+    fn resolution(&self) -> Resolution {
+        self.inner.resolution()
+    }
+    fn iter(&self) -> Self::Iterator {
+        self.inner.iter()
+    }
+    fn flush_after(&self) -> bool {
+        self.inner.flush_after()
+    }
+    fn clear_framebuffer(&self) -> Option<bool> {
+        self.inner.clear_framebuffer()
+    }
+    fn shift(&self) -> Option<Shift> {
+        self.header.shift
+    }
+    fn copies(&self) -> Copies<'a> {
+        self.header.copies.clone()
+    }
+    fn xor(&self) -> bool {
+        self.inner.xor()
+    }
+}
@@ -0,0 +1,241 @@
+use super::{BlockCopy, Decoder, ParseError, Shift};
+use crate::{utils::spans, Leaf, LeafData, Resolution};
+
+/// Memory layout of a `Framebuffer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Rows of `Resolution::stride` bytes, most significant bit first, same as the encoder input
+    RowMajor,
+    /// SSD1306 style pages of 8 rows, one byte per column with the top row in the least
+    /// significant bit
+    Pages,
+}
+
+impl Layout {
+    /// Bytes taken by an image of the given resolution
+    pub fn buf_len(self, resolution: Resolution) -> usize {
+        match self {
+            Self::RowMajor => resolution.buf_len(),
+            Self::Pages => resolution.width() as usize * (resolution.height() as usize).div_ceil(8),
+        }
+    }
+}
+
+/// Draws decoded frames straight into a caller-provided buffer, without embedded-graphics.
+///
+/// Leaves are clipped to the resolution, the padding of the tree is never drawn.
+#[derive(Debug)]
+pub struct Framebuffer<'b> {
+    buf: &'b mut [u8],
+    resolution: Resolution,
+    layout: Layout,
+}
+
+impl<'b> Framebuffer<'b> {
+    /// Returns `None` if `buf` is shorter than `layout.buf_len(resolution)`.
+    pub fn new(buf: &'b mut [u8], resolution: Resolution, layout: Layout) -> Option<Self> {
+        let buf = buf.get_mut(..layout.buf_len(resolution))?;
+        Some(Self {
+            buf,
+            resolution,
+            layout,
+        })
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.buf
+    }
+
+    pub fn clear(&mut self, value: bool) {
+        self.buf.fill(if value { 0xff } else { 0 });
+    }
+
+    /// Returns `false` outside of the image.
+    pub fn get_pixel(&self, x: u32, y: u32) -> bool {
+        match self.locate(x, y) {
+            Some((index, mask)) => self.buf[index] & mask != 0,
+            None => false,
+        }
+    }
+
+    /// Pixels outside of the image are ignored.
+    pub fn set_pixel(&mut self, x: u32, y: u32, value: bool) {
+        if let Some((index, mask)) = self.locate(x, y) {
+            set_bits(&mut self.buf[index], mask, value);
+        }
+    }
+
+    /// Fills a rectangle a byte at a time, clipped to the image.
+    pub fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, value: bool) {
+        self.update_rect(x, y, width, height, |byte, mask| set_bits(byte, mask, value));
+    }
+
+    /// Inverts the pixels of a rectangle, clipped to the image.
+    pub fn toggle_rect(&mut self, x: u32, y: u32, width: u32, height: u32) {
+        self.update_rect(x, y, width, height, |byte, mask| *byte ^= mask);
+    }
+
+    /// Calls `update` with each byte covering the rectangle and the mask of its covered bits.
+    fn update_rect(
+        &mut self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        update: impl Fn(&mut u8, u8),
+    ) {
+        let x0 = x.min(self.resolution.width() as u32) as usize;
+        let y0 = y.min(self.resolution.height() as u32) as usize;
+        let x1 = x.saturating_add(width).min(self.resolution.width() as u32) as usize;
+        let y1 = y
+            .saturating_add(height)
+            .min(self.resolution.height() as u32) as usize;
+        if x0 >= x1 || y0 >= y1 {
+            return;
+        }
+
+        match self.layout {
+            Layout::RowMajor => {
+                let stride = self.resolution.stride();
+                for row in self.buf.chunks_exact_mut(stride).take(y1).skip(y0) {
+                    for (byte, lo, hi) in spans(x0, x1) {
+                        // most significant bit first
+                        let mask = (0xff >> lo) & (0xff00u16 >> hi) as u8;
+                        update(&mut row[byte], mask);
+                    }
+                }
+            }
+            Layout::Pages => {
+                let width = self.resolution.width() as usize;
+                for (page, lo, hi) in spans(y0, y1) {
+                    // top row in the least significant bit
+                    let mask = (0xff << lo) & (0xff >> (8 - hi));
+                    for column in &mut self.buf[page * width..][x0..x1] {
+                        update(column, mask);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn draw_leaf(&mut self, leaf: &Leaf) {
+        let (x, y) = leaf.origin();
+        match leaf.data {
+            LeafData::Feature(f) => self.fill_rect(x, y, leaf.side(), leaf.side(), f),
+            LeafData::Bitmap(b) => {
+                let bits = u16::from_be_bytes(b);
+                for i in 0..16 {
+                    let value = bits << i & 0x8000 != 0;
+                    self.set_pixel(x + i % 4, y + i / 4, value);
+                }
+            }
+        }
+    }
+
+    /// Inverts the pixels covered by the lit pixels of a leaf, see `FrameMeta::xor`.
+    pub fn toggle_leaf(&mut self, leaf: &Leaf) {
+        let (x, y) = leaf.origin();
+        match leaf.data {
+            LeafData::Feature(true) => self.toggle_rect(x, y, leaf.side(), leaf.side()),
+            LeafData::Feature(false) => (),
+            LeafData::Bitmap(b) => {
+                let bits = u16::from_be_bytes(b);
+                for i in (0..16).filter(|i| bits << i & 0x8000 != 0) {
+                    let (x, y) = (x + i % 4, y + i / 4);
+                    self.set_pixel(x, y, !self.get_pixel(x, y));
+                }
+            }
+        }
+    }
+
+    /// Moves the image right and down by `dx` and `dy` pixels, left and up if negative.
+    ///
+    /// The uncovered pixels are unlit.
+    pub fn shift(&mut self, dx: i16, dy: i16) {
+        let (width, height) = (self.resolution.width(), self.resolution.height());
+        self.move_rect((0, 0), (width, height), (dx, dy));
+    }
+
+    /// Copies a square from its offset in the buffer, see `BlockCopy`.
+    pub fn copy_block(&mut self, copy: &BlockCopy) {
+        self.move_rect((copy.x, copy.y), (copy.side, copy.side), (copy.dx, copy.dy));
+    }
+
+    /// Fills a rectangle with the pixels `(dx, dy)` away from it, unlit outside of the image.
+    ///
+    /// The source and the rectangle can overlap, the source is read before it's overwritten.
+    fn move_rect(&mut self, (x, y): (u16, u16), (w, h): (u16, u16), (dx, dy): Shift) {
+        let (width, height) = (
+            self.resolution.width() as i32,
+            self.resolution.height() as i32,
+        );
+        let (x, y) = (x as i32, y as i32);
+        let (w, h) = ((w as i32).min(width - x), (h as i32).min(height - y));
+        let (dx, dy) = (dx as i32, dy as i32);
+        // walk away from the direction of the move, so pixels are read before being overwritten
+        for row in 0..h {
+            let y = y + if dy > 0 { h - 1 - row } else { row };
+            for column in 0..w {
+                let x = x + if dx > 0 { w - 1 - column } else { column };
+                let (sx, sy) = (x - dx, y - dy);
+                let value = (0..width).contains(&sx)
+                    && (0..height).contains(&sy)
+                    && self.get_pixel(sx as u32, sy as u32);
+                self.set_pixel(x as u32, y as u32, value);
+            }
+        }
+    }
+
+    /// Draws a frame, moving, copying or clearing the buffer first if the decoder asks for it.
+    ///
+    /// The leaves of XOR frames toggle the pixels instead.
+    ///
+    /// Drawing stops at the first invalid leaf, which is returned as an error.
+    pub fn draw<'a, D: Decoder<'a>>(&mut self, dec: &D) -> Result<(), ParseError> {
+        if let Some((dx, dy)) = dec.shift() {
+            self.shift(dx, dy);
+        }
+        for copy in dec.copies() {
+            self.copy_block(&copy);
+        }
+        if let Some(c) = dec.clear_framebuffer() {
+            self.clear(c);
+        }
+        for leaf in dec.iter() {
+            if dec.xor() {
+                self.toggle_leaf(&leaf?);
+            } else {
+                self.draw_leaf(&leaf?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Byte index and bit mask of a pixel
+    fn locate(&self, x: u32, y: u32) -> Option<(usize, u8)> {
+        if x >= self.resolution.width() as u32 || y >= self.resolution.height() as u32 {
+            return None;
+        }
+        let (x, y) = (x as usize, y as usize);
+        Some(match self.layout {
+            Layout::RowMajor => (y * self.resolution.stride() + x / 8, 0x80 >> (x % 8)),
+            Layout::Pages => ((y / 8) * self.resolution.width() as usize + x, 1 << (y % 8)),
+        })
+    }
+}
+
+fn set_bits(byte: &mut u8, mask: u8, value: bool) {
+    if value {
+        *byte |= mask;
+    } else {
+        *byte &= !mask;
+    }
+}
@@ -0,0 +1,182 @@
+use super::{video::parse_any, AnyDecoder, Decoder, FromBuf, Motion, ParseError};
+use crate::{Resolution, VideoHeader};
+
+/// Source of bytes for `VideoStream`, e.g. external flash read over SPI.
+///
+/// Implemented for closures that fill the buffer they're passed and return how many bytes they
+/// read, zero meaning the end of the stream.
+/// A `std::io::Read` can be wrapped as `|buf: &mut [u8]| reader.read(buf)`.
+pub trait ByteSource {
+    type Error;
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+impl<F, E> ByteSource for F
+where
+    F: FnMut(&mut [u8]) -> Result<usize, E>,
+{
+    type Error = E;
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, E> {
+        self(buf)
+    }
+}
+
+/// Error returned when pulling frames from a `ByteSource`.
+#[derive(Debug, PartialEq)]
+pub enum ReadError<E> {
+    Parse(ParseError),
+    Read(E),
+    /// The frame doesn't fit in the buffer of the stream
+    FrameTooLarge,
+}
+
+impl<E> From<ParseError> for ReadError<E> {
+    fn from(e: ParseError) -> Self {
+        Self::Parse(e)
+    }
+}
+
+/// Reads length-prefixed frames one at a time into a buffer of `N` bytes.
+///
+/// Frames borrow the buffer, so there's no `Iterator` impl: call `next_frame` until it returns
+/// `None`, dropping the previous frame first.
+/// `N` has to fit the largest frame of the stream, about 1KiB for 128x64 video.
+pub struct VideoStream<S, const N: usize> {
+    source: S,
+    buf: [u8; N],
+    resolution: Resolution,
+    header: Option<VideoHeader>,
+    progress: Progress,
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    /// Frames left to read, known from the header of some containers
+    frames_left: Option<u32>,
+    /// The stream ends with a seek index
+    indexed: bool,
+    done: bool,
+}
+
+impl Progress {
+    /// Counts down the frames left, stopping at errors and after the last frame.
+    fn count<'a, D: Decoder<'a>, E>(&mut self, res: &Result<D, ReadError<E>>) {
+        match res {
+            Ok(dec) if dec.flush_after() => {
+                if let Some(left) = self.frames_left.as_mut() {
+                    *left -= 1;
+                    self.done |= *left == 0;
+                }
+            }
+            Ok(_) => (),
+            Err(_) => self.done = true,
+        }
+    }
+}
+
+impl<S: ByteSource, const N: usize> VideoStream<S, N> {
+    /// Reads a bare stream of 128x64 frames.
+    pub fn new(source: S) -> Self {
+        Self::with_resolution(source, Resolution::default())
+    }
+
+    pub fn with_resolution(source: S, resolution: Resolution) -> Self {
+        Self {
+            source,
+            buf: [0; N],
+            resolution,
+            header: None,
+            progress: Default::default(),
+        }
+    }
+
+    /// Reads the `VideoHeader` at the start of a container.
+    ///
+    /// If the header records the frame count, reading stops after the last frame.
+    /// Otherwise reading stops at the seek index, if the container has one.
+    pub fn with_header(mut source: S) -> Result<Self, ReadError<S::Error>> {
+        let mut buf = [0; VideoHeader::LEN];
+        if !read_exact(&mut source, &mut buf)? {
+            return Err(ParseError::InvalidHeader.into());
+        }
+        let header = VideoHeader::parse(&buf)?;
+
+        let mut stream = Self::with_resolution(source, header.resolution);
+        stream.progress.frames_left = Some(header.frame_count).filter(|c| *c > 0);
+        stream.progress.indexed = header.indexed;
+        stream.header = Some(header);
+        Ok(stream)
+    }
+
+    pub fn header(&self) -> Option<&VideoHeader> {
+        self.header.as_ref()
+    }
+
+    /// Reads the next frame and parses it with `D`.
+    pub fn next_frame<'a, D: FromBuf<'a>>(&'a mut self) -> Option<Result<D, ReadError<S::Error>>> {
+        let resolution = self.resolution;
+        let res = read_frame(&mut self.source, &mut self.buf, &mut self.progress)
+            .transpose()?
+            .and_then(|buf| Ok(D::with_resolution(buf, resolution)?));
+        self.progress.count(&res);
+        Some(res)
+    }
+
+    /// Reads the next frame and parses it in the format recorded in the header.
+    ///
+    /// Bare streams don't record their format, `next_frame` has to be used for those.
+    pub fn next_any(&mut self) -> Option<Result<Motion<'_, AnyDecoder<'_>>, ReadError<S::Error>>> {
+        let header = match self.header {
+            Some(h) => h,
+            None => return Some(Err(ParseError::UnsupportedFormat.into())),
+        };
+        let resolution = self.resolution;
+        let res = read_frame(&mut self.source, &mut self.buf, &mut self.progress)
+            .transpose()?
+            .and_then(|buf| Ok(parse_any(buf, header.format, header.motion, resolution)?));
+        self.progress.count(&res);
+        Some(res)
+    }
+}
+
+/// Reads the next frame into `buf`, `None` at the end of the stream.
+fn read_frame<'a, S: ByteSource>(
+    source: &mut S,
+    buf: &'a mut [u8],
+    progress: &mut Progress,
+) -> Result<Option<&'a [u8]>, ReadError<S::Error>> {
+    if progress.done {
+        return Ok(None);
+    }
+    let mut len = [0; 2];
+    if !read_exact(source, &mut len)? {
+        progress.done = true;
+        return Ok(None);
+    }
+    let len = u16::from_le_bytes(len) as usize;
+    // frames are never empty, the seek index starts with frame 0 at offset 0, or no entries
+    if len == 0 && progress.indexed {
+        progress.done = true;
+        return Ok(None);
+    }
+    let buf = buf.get_mut(..len).ok_or(ReadError::FrameTooLarge)?;
+    if !read_exact(source, buf)? {
+        return Err(ParseError::Truncated.into());
+    }
+    Ok(Some(buf))
+}
+
+/// Fills `buf` from the source.
+///
+/// Returns `false` if the source ended before the first byte, `Truncated` if it ended later.
+fn read_exact<S: ByteSource>(source: &mut S, buf: &mut [u8]) -> Result<bool, ReadError<S::Error>> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match source.read(&mut buf[filled..]).map_err(ReadError::Read)? {
+            0 if filled == 0 => return Ok(false),
+            0 => return Err(ParseError::Truncated.into()),
+            n => filled += n,
+        }
+    }
+    Ok(true)
+}
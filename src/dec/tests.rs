@@ -1,5 +1,10 @@
-use super::{Decoder, LeafParserV1, LeafParserV2};
-use crate::{dec::Leaf, LeafData};
+#![allow(clippy::unusual_byte_groupings)]
+
+use super::{
+    video::VideoSlice, Decoder, FromBuf, LeafParserV1, LeafParserV2, LeafParserV3, LeafParserV4,
+    ParseError,
+};
+use crate::{dec::Leaf, LeafData, Resolution};
 
 use embedded_graphics::{mock_display, prelude::ImageDrawable, Drawable};
 use heapless::Vec;
@@ -25,22 +30,27 @@ fn parse() {
         Leaf {
             pos: Vec::from_slice(&[1, 1]).unwrap(),
             data: LeafData::Feature(true),
+            root: 7,
         },
         Leaf {
             pos: Vec::from_slice(&[1, 3, 1]).unwrap(),
             data: LeafData::Feature(true),
+            root: 7,
         },
         Leaf {
             pos: Vec::from_slice(&[1, 3, 3, 1]).unwrap(),
             data: LeafData::Feature(true),
+            root: 7,
         },
         Leaf {
             pos: Vec::from_slice(&[1, 3, 3, 3, 1]).unwrap(),
             data: LeafData::Feature(true),
+            root: 7,
         },
         Leaf {
             pos: Vec::from_slice(&[1, 3, 3, 3, 3]).unwrap(),
             data: LeafData::Bitmap([0xf0, 0x0f]),
+            root: 7,
         },
     ];
 
@@ -48,7 +58,7 @@ fn parse() {
     let iter = parser.iter().zip(expect);
 
     for (p, e) in iter {
-        assert_eq!(p, e)
+        assert_eq!(p.unwrap(), e)
     }
 }
 
@@ -57,6 +67,7 @@ fn draw_full() {
     let leaf = Leaf {
         pos: Vec::from_slice(&[0]).unwrap(),
         data: LeafData::Feature(true),
+        root: 7,
     };
     let mut display = mock_display::MockDisplay::new();
 
@@ -139,22 +150,27 @@ fn draw_stairs() {
         Leaf {
             pos: Vec::from_slice(&[0, 3]).unwrap(),
             data: LeafData::Feature(true),
+            root: 7,
         },
         Leaf {
             pos: Vec::from_slice(&[0, 2, 3]).unwrap(),
             data: LeafData::Feature(true),
+            root: 7,
         },
         Leaf {
             pos: Vec::from_slice(&[0, 2, 2, 3]).unwrap(),
             data: LeafData::Feature(true),
+            root: 7,
         },
         Leaf {
             pos: Vec::from_slice(&[0, 2, 2, 2, 3]).unwrap(),
             data: LeafData::Feature(true),
+            root: 7,
         },
         Leaf {
             pos: Vec::from_slice(&[0, 2, 2, 2, 2]).unwrap(),
             data: LeafData::Bitmap([0, 0b0011_0111]),
+            root: 7,
         },
     ];
 
@@ -298,3 +314,162 @@ fn parse_draw_stairs_v2() {
         .unwrap();
     display.assert_pattern(&STAIR_PATTERN_BUF_CLEARED)
 }
+
+#[test]
+fn parse_errors_v1() {
+    let errors = |buf: &[u8], res| -> std::vec::Vec<_> {
+        LeafParserV1::with_resolution(buf, res)
+            .unwrap()
+            .iter()
+            .filter_map(Result::err)
+            .collect()
+    };
+    let res = Resolution::default();
+
+    // second byte of a depth 3 leaf is missing
+    assert_eq!(errors(&[1, 0b1_011_01_11], res), [ParseError::Truncated]);
+    // bitmap data is cut short
+    assert_eq!(
+        errors(&[1, 0b1_110_01_11, 0b11_11_11_00, 0xf0], res),
+        [ParseError::Truncated]
+    );
+    // depth 7 with the discriminant bit set
+    assert_eq!(
+        errors(&[1, 0b1_111_00_00, 0], res),
+        [ParseError::InvalidDepth]
+    );
+    // depth 5 in a 16x16 tree, which is only 4 levels deep
+    let small = Resolution::new(16, 16).unwrap();
+    assert_eq!(
+        errors(&[1, 0b1_101_00_00, 0, 0b1_001_11_00], small),
+        [ParseError::InvalidDepth]
+    );
+}
+
+#[test]
+fn parse_errors_v2() {
+    let errors = |buf: &[u8], res| -> std::vec::Vec<_> {
+        LeafParserV2::with_resolution(buf, res)
+            .unwrap()
+            .iter()
+            .filter_map(Result::err)
+            .collect()
+    };
+    let res = Resolution::new(4, 4).unwrap();
+
+    assert!(errors(&[0b01_10_11_10, 0b11_00_00_00], res).is_empty());
+    // tree ends after the third child
+    assert_eq!(errors(&[0b01_10_11_10], res), [ParseError::Truncated]);
+    // a full byte after the tree
+    assert_eq!(errors(&[0b11_00_00_00, 0], res), [ParseError::TrailingData]);
+    // set bits in the padding
+    assert_eq!(errors(&[0b11_00_01_00], res), [ParseError::TrailingData]);
+    // three levels of branches in a 4x4 tree
+    assert_eq!(errors(&[0b01_01_01_10], res), [ParseError::TreeOverflow]);
+    assert_eq!(errors(&[], res), [ParseError::Truncated]);
+}
+
+#[test]
+fn parse_v3() {
+    let parse = |buf| -> std::vec::Vec<_> {
+        LeafParserV3::with_resolution(buf, Resolution::new(4, 4).unwrap())
+            .unwrap()
+            .iter()
+            .collect()
+    };
+
+    // 4x4 branch stored as a bitmap
+    assert_eq!(
+        parse(&[0b01_1_10100, 0b101_00001, 0b111_00000]),
+        [Ok(Leaf {
+            data: LeafData::Bitmap([0b1010_0101, 0b0000_1111]),
+            pos: Default::default(),
+            root: 2,
+        })]
+    );
+    // regular branch
+    assert_eq!(parse(&[0b01_0_10_11_1, 0b0_11_00000]).len(), 4);
+    assert_eq!(parse(&[0b01_1_10100]), [Err(ParseError::Truncated)]);
+}
+
+#[test]
+fn parse_errors_v4() {
+    let errors = |buf: &[u8]| -> std::vec::Vec<_> {
+        LeafParserV4::with_resolution(buf, Resolution::new(4, 4).unwrap())
+            .unwrap()
+            .iter()
+            .filter_map(Result::err)
+            .collect()
+    };
+
+    // trailing zeros are trimmed, an empty root takes no bytes at all
+    assert!(errors(&[]).is_empty());
+    assert!(errors(&[0, 0, 0, 0]).is_empty());
+    assert_eq!(errors(&[0, 0, 0, 0, 0]), [ParseError::TrailingData]);
+}
+
+#[test]
+fn video_truncated() {
+    let buf = [1, 0, 0, 5, 0, 0, 0];
+    let mut frames = VideoSlice::<LeafParserV1>::new(&buf);
+
+    assert!(frames.next().unwrap().is_ok());
+    assert_eq!(frames.next().unwrap(), Err(ParseError::Truncated));
+    assert!(frames.next().is_none());
+}
+
+#[test]
+fn motion_header() {
+    use super::{
+        raw::{Framebuffer, Layout},
+        BlockCopy, FrameHeader, Motion,
+    };
+
+    type Parser<'a> = Motion<'a, LeafParserV2<'a>>;
+    let res = Resolution::new(4, 4).unwrap();
+    // a plain empty frame, the previous frame moved by (1, -2), then the 2x2 square at (2, 2)
+    // copied from (1, 0), all without new leaves
+    #[rustfmt::skip]
+    let buf = [
+        2, 0, 0, 0b00_000000,
+        6, 0, 1, 1, 0, 0xfe, 0xff, 0b00_000000,
+        13, 0, 2, 1, 0, 2, 0, 2, 0, 1, 1, 0, 2, 0, 0b00_000000,
+    ];
+    let mut frames = VideoSlice::<Parser>::with_resolution(&buf, res);
+
+    let mut rows = [0; 4];
+    let mut fb = Framebuffer::new(&mut rows, res, Layout::RowMajor).unwrap();
+    fb.fill_rect(0, 2, 2, 2, true);
+    let dec = frames.next().unwrap().unwrap();
+    assert!(dec.header().is_plain());
+
+    let dec = frames.next().unwrap().unwrap();
+    assert_eq!(dec.shift(), Some((1, -2)));
+    fb.draw(&dec).unwrap();
+    assert_eq!(fb.as_bytes(), [0b0110_0000, 0b0110_0000, 0, 0]);
+
+    let dec = frames.next().unwrap().unwrap();
+    assert_eq!(dec.shift(), None);
+    let copy = BlockCopy {
+        x: 2,
+        y: 2,
+        side: 2,
+        dx: 1,
+        dy: 2,
+    };
+    assert!(dec.copies().eq([copy]));
+    fb.draw(&dec).unwrap();
+    assert_eq!(
+        fb.as_bytes(),
+        [0b0110_0000, 0b0110_0000, 0b0011_0000, 0b0011_0000]
+    );
+
+    let error = |buf| FrameHeader::parse(buf).unwrap_err();
+    assert_eq!(error(&[1, 0, 0]), ParseError::Truncated);
+    assert_eq!(error(&[2, 1, 0]), ParseError::Truncated);
+    assert_eq!(error(&[4]), ParseError::InvalidHeader);
+    assert_eq!(error(&[]), ParseError::Truncated);
+    // squares are aligned to their side
+    let misaligned = [2, 1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0];
+    assert_eq!(error(&misaligned), ParseError::InvalidHeader);
+}
@@ -1,44 +1,227 @@
-use core::{convert::TryInto, marker::PhantomData};
+use core::convert::TryInto;
 
-use super::Decoder;
+use super::{raw::Framebuffer, AnyDecoder, FrameHeader, Motion, ParseError, ParseFrame};
+use crate::{Resolution, TreeFormat, VideoHeader};
 
+#[cfg(feature = "graphics")]
+use super::DrawError;
+#[cfg(feature = "graphics")]
+use embedded_graphics::{image::ImageDrawable, pixelcolor::BinaryColor, prelude::DrawTarget};
+
+/// Iterates over a stream of length-prefixed frames.
 #[derive(Debug)]
-pub struct VideoSlice<'a, D> {
+pub struct VideoSlice<'a, D: ParseFrame<'a>> {
     buf: &'a [u8],
     index: usize,
-    _dec: PhantomData<D>,
+    resolution: Resolution,
+    format: D::Format,
+    /// `(frame, offset)` pairs of the I-frames, see `VideoHeader`
+    seek_index: &'a [u8],
 }
 
-impl<'a, D: Decoder<'a>> VideoSlice<'a, D> {
+impl<'a, D: ParseFrame<'a, Format = ()>> VideoSlice<'a, D> {
+    /// Iterates over 128x64 frames.
     pub fn new(buf: &'a [u8]) -> Self {
+        Self::with_resolution(buf, Resolution::default())
+    }
+
+    pub fn with_resolution(buf: &'a [u8], resolution: Resolution) -> Self {
         Self {
             buf,
             index: 0,
-            _dec: PhantomData,
+            resolution,
+            format: (),
+            seek_index: &[],
         }
     }
 }
 
-impl<'a, D: Decoder<'a>> Iterator for VideoSlice<'a, D> {
-    type Item = D;
+impl<'a, D: ParseFrame<'a>> VideoSlice<'a, D> {
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let start = self.index + 2;
+    /// Jumps to the I-frame preceding `frame` and draws the frames in between into `target`,
+    /// so that the next item is `frame` itself.
+    ///
+    /// Without a seek index the stream is replayed from the start.
+    /// Returns `false` if the stream ends before `frame`.
+    /// Shifted frames, block copies and XOR frames can't be drawn this way and return
+    /// `UnsupportedFormat`,
+    /// see `seek_framebuffer`.
+    #[cfg(feature = "graphics")]
+    pub fn seek<DT>(&mut self, frame: u32, target: &mut DT) -> Result<bool, DrawError<DT::Error>>
+    where
+        DT: DrawTarget<Color = BinaryColor>,
+    {
+        self.seek_with(frame, |dec| {
+            if dec.shift().is_some() || dec.copies().next().is_some() || dec.xor() {
+                return Err(ParseError::UnsupportedFormat.into());
+            }
+            dec.drawable().draw(target).map_err(DrawError::Draw)
+        })
+    }
 
-        if self.buf.len() >= start {
-            let frame_size =
-                u16::from_le_bytes(self.buf[self.index..start].try_into().unwrap()) as usize;
+    /// Same as `seek`, drawing into a raw framebuffer.
+    pub fn seek_framebuffer(
+        &mut self,
+        frame: u32,
+        target: &mut Framebuffer,
+    ) -> Result<bool, ParseError> {
+        self.seek_with(frame, |dec| target.draw(&dec))
+    }
 
-            let end = start + frame_size;
-            self.index = end;
+    fn seek_with<E, F>(&mut self, frame: u32, mut draw: F) -> Result<bool, E>
+    where
+        E: From<ParseError>,
+        F: FnMut(D) -> Result<(), E>,
+    {
+        let (i_frame, offset) = self
+            .seek_index
+            .chunks_exact(8)
+            .map(|e| {
+                let frame = u32::from_le_bytes(e[..4].try_into().unwrap());
+                let offset = u32::from_le_bytes(e[4..].try_into().unwrap());
+                (frame, offset as usize)
+            })
+            .take_while(|(f, _)| *f <= frame)
+            .last()
+            .unwrap_or((0, 0));
 
-            if self.buf.len() >= end {
-                Self::Item::from_buf(&self.buf[start..end]).ok()
-            } else {
-                None
+        self.index = offset;
+        for _ in i_frame..frame {
+            loop {
+                let dec = match self.next() {
+                    Some(d) => d?,
+                    None => return Ok(false),
+                };
+                dec.validate()?;
+                let flush = dec.flush_after();
+                draw(dec)?;
+                if flush {
+                    break;
+                }
             }
+        }
+
+        Ok(self.index < self.buf.len())
+    }
+}
+
+/// A video stream starting with a `VideoHeader`.
+#[derive(Debug, Clone, Copy)]
+pub struct Video<'a> {
+    header: VideoHeader,
+    frames: &'a [u8],
+    seek_index: &'a [u8],
+}
+
+impl<'a> Video<'a> {
+    pub fn new(buf: &'a [u8]) -> Result<Self, ParseError> {
+        let header = VideoHeader::parse(buf)?;
+        let body = &buf[VideoHeader::LEN..];
+
+        let (frames, seek_index) = if header.indexed {
+            split_seek_index(body).ok_or(ParseError::InvalidHeader)?
         } else {
-            None
+            (body, &[][..])
+        };
+
+        Ok(Self {
+            header,
+            frames,
+            seek_index,
+        })
+    }
+
+    pub fn header(&self) -> &VideoHeader {
+        &self.header
+    }
+
+    /// Iterates over the frames, decoded in the format recorded in the header.
+    ///
+    /// Frames of streams without frame headers never move the previous frame.
+    pub fn frames(&self) -> VideoSlice<'a, Motion<'a, AnyDecoder<'a>>> {
+        VideoSlice {
+            buf: self.frames,
+            index: 0,
+            resolution: self.header.resolution,
+            format: (self.header.format, self.header.motion),
+            seek_index: self.seek_index,
+        }
+    }
+}
+
+/// Frames of a container, in the tree format of the header and with frame headers if the
+/// stream has them.
+impl<'a> ParseFrame<'a> for Motion<'a, AnyDecoder<'a>> {
+    type Format = (TreeFormat, bool);
+    fn parse_frame(
+        buf: &'a [u8],
+        (format, motion): Self::Format,
+        resolution: Resolution,
+    ) -> Result<Self, ParseError> {
+        parse_any(buf, format, motion, resolution)
+    }
+}
+
+/// Parses a frame of a container, splitting the frame header first if the stream has them.
+pub(super) fn parse_any(
+    buf: &[u8],
+    format: TreeFormat,
+    motion: bool,
+    resolution: Resolution,
+) -> Result<Motion<'_, AnyDecoder<'_>>, ParseError> {
+    if motion {
+        let (header, tree) = FrameHeader::parse(buf)?;
+        Ok(Motion::new(
+            AnyDecoder::new(tree, format, resolution)?,
+            header,
+        ))
+    } else {
+        Ok(Motion::plain(AnyDecoder::new(buf, format, resolution)?))
+    }
+}
+
+/// Splits the frames from the seek index at the end of the stream.
+fn split_seek_index(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (rest, magic) = buf.split_at(buf.len().checked_sub(4)?);
+    if magic != VideoHeader::INDEX_MAGIC {
+        return None;
+    }
+    let (rest, count) = rest.split_at(rest.len().checked_sub(4)?);
+    let len = (u32::from_le_bytes(count.try_into().unwrap()) as usize).checked_mul(8)?;
+
+    Some(rest.split_at(rest.len().checked_sub(len)?))
+}
+
+impl<'a, D: ParseFrame<'a>> Iterator for VideoSlice<'a, D> {
+    type Item = Result<D, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.buf.len() {
+            return None;
+        }
+
+        let start = self.index + 2;
+        let end = self
+            .buf
+            .get(self.index..start)
+            .map(|len| start + u16::from_le_bytes(len.try_into().unwrap()) as usize);
+
+        match end {
+            Some(end) if end <= self.buf.len() => {
+                self.index = end;
+                Some(D::parse_frame(
+                    &self.buf[start..end],
+                    self.format,
+                    self.resolution,
+                ))
+            }
+            _ => {
+                self.index = self.buf.len();
+                Some(Err(ParseError::Truncated))
+            }
         }
     }
 }
@@ -46,7 +229,7 @@ impl<'a, D: Decoder<'a>> Iterator for VideoSlice<'a, D> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::dec::{LeafParserV1, LeafParserV2};
+    use crate::dec::{FromBuf, LeafParserV1, LeafParserV2};
 
     #[test]
     fn parse() {
@@ -67,7 +250,7 @@ mod tests {
         ];
 
         for (l, r) in expect_v1.iter().zip(VideoSlice::<LeafParserV1>::new(&buf)) {
-            assert_eq!(*l, r)
+            assert_eq!(*l, r.unwrap())
         }
 
         let expect_v2 = [
@@ -76,7 +259,7 @@ mod tests {
             LeafParserV2::from_buf(&buf[10..14]).unwrap(),
         ];
         for (l, r) in expect_v2.iter().zip(VideoSlice::<LeafParserV2>::new(&buf)) {
-            assert_eq!(*l, r)
+            assert_eq!(*l, r.unwrap())
         }
     }
 }
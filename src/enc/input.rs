@@ -0,0 +1,311 @@
+use super::mono::Binarize;
+use crate::Resolution;
+
+use image::{
+    codecs::gif::GifDecoder, imageops, AnimationDecoder, DynamicImage, GenericImageView, GrayImage,
+    ImageFormat,
+};
+use std::{
+    convert::TryFrom,
+    fmt,
+    io::{self, BufRead, Read},
+};
+
+/// Error returned when reading an image into a monochrome buffer.
+#[derive(Debug)]
+pub enum InputError {
+    Image(image::ImageError),
+    /// The image doesn't have the resolution the encoder was told to expect
+    SizeMismatch {
+        expected: Resolution,
+        found: (u32, u32),
+    },
+    Io(io::Error),
+    /// The Y4M stream is malformed or uses a format other than 8-bit YUV
+    InvalidY4m(&'static str),
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Image(e) => write!(f, "can't read the image: {}", e),
+            Self::SizeMismatch { expected, found } => write!(
+                f,
+                "the image is {}x{}, expected {}x{}",
+                found.0,
+                found.1,
+                expected.width(),
+                expected.height()
+            ),
+            Self::Io(e) => write!(f, "can't read the input: {}", e),
+            Self::InvalidY4m(e) => write!(f, "invalid Y4M stream: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for InputError {}
+
+impl From<image::ImageError> for InputError {
+    fn from(e: image::ImageError) -> Self {
+        Self::Image(e)
+    }
+}
+
+impl From<io::Error> for InputError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Recognizes Netpbm (P1 to P6), BMP and PNG files from their first bytes.
+///
+/// Anything else is assumed to be a raw bitmap.
+pub fn detect(data: &[u8]) -> Option<ImageFormat> {
+    match image::guess_format(data).ok()? {
+        f @ (ImageFormat::Pnm | ImageFormat::Bmp | ImageFormat::Png) => Some(f),
+        _ => None,
+    }
+}
+
+/// Decodes an image into a row-major, MSB-first buffer as taken by `QuadTree::from_buf`.
+///
+/// Colors are reduced to their brightness before `binarize` is applied, lit pixels are the
+/// bright ones, so set PBM pixels come out as `0`.
+pub fn load_image(
+    data: &[u8],
+    format: ImageFormat,
+    resolution: Resolution,
+    binarize: Binarize,
+) -> Result<Vec<u8>, InputError> {
+    let image = image::load_from_memory_with_format(data, format)?;
+    if image.dimensions() != (resolution.width() as u32, resolution.height() as u32) {
+        return Err(InputError::SizeMismatch {
+            expected: resolution,
+            found: image.dimensions(),
+        });
+    }
+
+    Ok(binarize.apply(image.to_luma8().as_raw(), resolution))
+}
+
+/// How frames of another size are brought to the resolution of the video
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    /// Resize to the resolution, stretching the image if the aspect ratio differs
+    #[default]
+    Scale,
+    /// Keep the center of the image at its size, padding with unlit pixels
+    Crop,
+}
+
+impl Fit {
+    fn apply(self, image: GrayImage, resolution: Resolution) -> GrayImage {
+        let (width, height) = (resolution.width() as u32, resolution.height() as u32);
+        if image.dimensions() == (width, height) {
+            return image;
+        }
+        match self {
+            Self::Scale => imageops::resize(&image, width, height, imageops::Triangle),
+            Self::Crop => {
+                let (w, h) = (image.width().min(width), image.height().min(height));
+                let center = imageops::crop_imm(
+                    &image,
+                    (image.width() - w) / 2,
+                    (image.height() - h) / 2,
+                    w,
+                    h,
+                );
+                let mut out = GrayImage::new(width, height);
+                imageops::replace(&mut out, &center, (width - w) / 2, (height - h) / 2);
+                out
+            }
+        }
+    }
+}
+
+/// Animations and videos `Frames` can read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceFormat {
+    Gif,
+    /// YUV4MPEG2, only the luma plane is used
+    Y4m,
+}
+
+/// Recognizes animated GIFs and Y4M streams from their first bytes.
+pub fn detect_sequence(data: &[u8]) -> Option<SequenceFormat> {
+    if data.starts_with(b"GIF8") {
+        Some(SequenceFormat::Gif)
+    } else if data.starts_with(b"YUV4MPEG2 ") {
+        Some(SequenceFormat::Y4m)
+    } else {
+        None
+    }
+}
+
+/// Decodes the frames of an animation one at a time into buffers as taken by
+/// `QuadTree::from_buf` or written to `VideoEncoder`.
+pub struct Frames<'a> {
+    frames: Box<dyn Iterator<Item = Result<GrayImage, InputError>> + 'a>,
+    frame_rate: Option<u16>,
+    resolution: Resolution,
+    fit: Fit,
+    binarize: Binarize,
+}
+
+impl<'a> Frames<'a> {
+    /// Reads the header of the animation, and the first frame of GIFs.
+    pub fn new<R: BufRead + 'a>(
+        reader: R,
+        format: SequenceFormat,
+        resolution: Resolution,
+        fit: Fit,
+        binarize: Binarize,
+    ) -> Result<Self, InputError> {
+        let (frames, frame_rate): (Box<dyn Iterator<Item = _>>, _) = match format {
+            SequenceFormat::Gif => {
+                let mut frames = GifDecoder::new(reader)?.into_frames();
+                // GIFs only record delays, the one of the first frame sets the rate
+                let first = frames.next().transpose()?;
+                let frame_rate = first.as_ref().and_then(|f| {
+                    let (numer, denom) = f.delay().numer_denom_ms();
+                    let rate =
+                        (1000 * denom as u64 + numer as u64 / 2).checked_div(numer as u64)?;
+                    u16::try_from(rate).ok().filter(|r| *r > 0)
+                });
+                let frames = first
+                    .into_iter()
+                    .map(Ok)
+                    .chain(frames)
+                    .map(|f| Ok(DynamicImage::ImageRgba8(f?.into_buffer()).into_luma8()));
+                (Box::new(frames), frame_rate)
+            }
+            SequenceFormat::Y4m => {
+                let y4m = Y4mFrames::new(reader)?;
+                let frame_rate = y4m.frame_rate;
+                (Box::new(y4m), frame_rate)
+            }
+        };
+        Ok(Self {
+            frames,
+            frame_rate,
+            resolution,
+            fit,
+            binarize,
+        })
+    }
+
+    /// Frames per second recorded in the input, if any
+    pub fn frame_rate(&self) -> Option<u16> {
+        self.frame_rate
+    }
+}
+
+impl Iterator for Frames<'_> {
+    type Item = Result<Vec<u8>, InputError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (resolution, fit, binarize) = (self.resolution, self.fit, self.binarize);
+        Some(self.frames.next()?.map(|image| {
+            let image = fit.apply(image, resolution);
+            binarize.apply(image.as_raw(), resolution)
+        }))
+    }
+}
+
+struct Y4mFrames<R> {
+    reader: R,
+    width: u32,
+    height: u32,
+    /// Bytes of the chroma planes following the luma one
+    chroma_len: usize,
+    frame_rate: Option<u16>,
+}
+
+impl<R: BufRead> Y4mFrames<R> {
+    fn new(mut reader: R) -> Result<Self, InputError> {
+        let header = read_line(&mut reader)?.ok_or(InputError::InvalidY4m("missing header"))?;
+        let mut params = header.split(|b| *b == b' ');
+        if params.next() != Some(b"YUV4MPEG2") {
+            return Err(InputError::InvalidY4m("missing header"));
+        }
+
+        let (mut width, mut height, mut frame_rate) = (None, None, None);
+        let mut colorspace = &b"420"[..];
+        for param in params {
+            let (tag, value) = match param.split_first() {
+                Some((tag, value)) => (tag, std::str::from_utf8(value).unwrap_or_default()),
+                None => continue,
+            };
+            match tag {
+                b'W' => width = value.parse::<u32>().ok(),
+                b'H' => height = value.parse::<u32>().ok(),
+                b'F' => {
+                    frame_rate = value.split_once(':').and_then(|(n, d)| {
+                        let (n, d) = (n.parse::<u32>().ok()?, d.parse::<u32>().ok()?);
+                        u16::try_from(n.checked_add(d / 2)?.checked_div(d)?).ok()
+                    })
+                }
+                b'C' => colorspace = &param[1..],
+                _ => (),
+            }
+        }
+        let (width, height) = match (width, height) {
+            (Some(w), Some(h)) if w > 0 && h > 0 => (w, h),
+            _ => return Err(InputError::InvalidY4m("missing or invalid resolution")),
+        };
+
+        let (w, h) = (width as usize, height as usize);
+        let chroma_len = match colorspace {
+            b"mono" => 0,
+            b"420" | b"420jpeg" | b"420paldv" | b"420mpeg2" => w.div_ceil(2) * h.div_ceil(2) * 2,
+            b"422" => w.div_ceil(2) * h * 2,
+            b"444" => w * h * 2,
+            b"444alpha" => w * h * 3,
+            _ => return Err(InputError::InvalidY4m("unsupported colorspace")),
+        };
+
+        Ok(Self {
+            reader,
+            width,
+            height,
+            chroma_len,
+            frame_rate: frame_rate.filter(|r| *r > 0),
+        })
+    }
+
+    fn read_frame(&mut self) -> Result<Option<GrayImage>, InputError> {
+        let header = match read_line(&mut self.reader)? {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        if !header.starts_with(b"FRAME") {
+            return Err(InputError::InvalidY4m("missing frame header"));
+        }
+
+        let mut luma = vec![0; self.width as usize * self.height as usize];
+        self.reader.read_exact(&mut luma)?;
+        let chroma = &mut (&mut self.reader).take(self.chroma_len as u64);
+        if io::copy(chroma, &mut io::sink())? < self.chroma_len as u64 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(GrayImage::from_raw(self.width, self.height, luma))
+    }
+}
+
+impl<R: BufRead> Iterator for Y4mFrames<R> {
+    type Item = Result<GrayImage, InputError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+/// Reads up to the next newline, `None` at the end of the input.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    match reader.read_until(b'\n', &mut line)? {
+        0 => Ok(None),
+        _ if line.pop() == Some(b'\n') => Ok(Some(line)),
+        _ => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{Resolution, MAX_DEPTH};
+
+use bitvec::prelude::*;
+
+/// How many wrong pixels a sector may have and still collapse into a single leaf.
+///
+/// Squares whose minority pixels are at most the allowance of their depth are filled with the
+/// majority color before the tree is built, so the minority pixels are lost.
+/// Depth 0 is the root square covering the whole image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Lossy {
+    max_error: [u32; MAX_DEPTH + 1],
+}
+
+impl Lossy {
+    /// Same allowance at every depth.
+    pub fn global(pixels: u32) -> Self {
+        Self {
+            max_error: [pixels; MAX_DEPTH + 1],
+        }
+    }
+
+    /// Allowances starting from the root, deeper sectors past the end stay lossless.
+    pub fn per_depth(pixels: &[u32]) -> Self {
+        let mut max_error = [0; MAX_DEPTH + 1];
+        for (max, p) in max_error.iter_mut().zip(pixels) {
+            *max = *p;
+        }
+        Self { max_error }
+    }
+
+    pub fn max_error(&self, depth: usize) -> u32 {
+        self.max_error.get(depth).copied().unwrap_or_default()
+    }
+
+    pub fn is_lossless(&self) -> bool {
+        self.max_error.iter().all(|e| *e == 0)
+    }
+
+    /// Fills the sectors within the allowance of a row-major buffer with their majority color.
+    ///
+    /// Returns how many pixels were flipped.
+    pub fn apply(&self, buf: &mut [u8], resolution: Resolution) -> PixelError {
+        assert_eq!(buf.len(), resolution.buf_len());
+        let mut error = PixelError {
+            wrong: 0,
+            total: resolution.width() as u32 * resolution.height() as u32,
+        };
+        if !self.is_lossless() {
+            let counts = SetCounts::new(buf, resolution);
+            let side = resolution.side();
+            self.apply_sector(buf, &counts, (0, 0), side, 0, &mut error.wrong);
+        }
+        error
+    }
+
+    fn apply_sector(
+        &self,
+        buf: &mut [u8],
+        counts: &SetCounts,
+        (x, y): (u32, u32),
+        side: u32,
+        depth: usize,
+        wrong: &mut u32,
+    ) {
+        let (w, h) = counts.clip(x, y, side);
+        if w == 0 || h == 0 {
+            return;
+        }
+        let set = counts.count(x, y, w, h);
+        let minority = set.min(w * h - set);
+        if minority == 0 {
+            return;
+        }
+        if minority <= self.max_error(depth) {
+            *wrong += minority;
+            let color = set * 2 > w * h;
+            let bits = buf.view_bits_mut::<Msb0>();
+            let stride = counts.resolution.stride() * 8;
+            for row in y..y + h {
+                let start = row as usize * stride + x as usize;
+                bits[start..start + w as usize].fill(color);
+            }
+        } else if side > 1 {
+            let half = side / 2;
+            for (dx, dy) in [(0, 0), (half, 0), (0, half), (half, half)] {
+                self.apply_sector(buf, counts, (x + dx, y + dy), half, depth + 1, wrong);
+            }
+        }
+    }
+
+    /// Updates what the decoder `shown` towards `frame`, leaving out the changes of the
+    /// sectors with at most the allowance of changed pixels.
+    ///
+    /// Pixels already left out count against the allowance, and the allowance of a sector
+    /// caps what its children leave out, so the error of every sector stays within its
+    /// allowance instead of piling up over the frames.
+    /// Returns how many pixels of `shown` still differ from `frame`.
+    pub fn apply_changes(
+        &self,
+        shown: &mut [u8],
+        frame: &[u8],
+        resolution: Resolution,
+    ) -> PixelError {
+        assert_eq!(shown.len(), resolution.buf_len());
+        assert_eq!(frame.len(), resolution.buf_len());
+        let changes: Vec<u8> = shown.iter().zip(frame).map(|(s, f)| s ^ f).collect();
+        let counts = SetCounts::new(&changes, resolution);
+        let side = resolution.side();
+        let wrong = self.changes_sector(shown, &counts, (0, 0), side, 0, None);
+        PixelError {
+            wrong,
+            total: resolution.width() as u32 * resolution.height() as u32,
+        }
+    }
+
+    /// Returns the changed pixels left out, at most `budget` if an outer sector set one.
+    fn changes_sector(
+        &self,
+        shown: &mut [u8],
+        counts: &SetCounts,
+        (x, y): (u32, u32),
+        side: u32,
+        depth: usize,
+        budget: Option<u32>,
+    ) -> u32 {
+        let (w, h) = counts.clip(x, y, side);
+        if w == 0 || h == 0 {
+            return 0;
+        }
+        let changed = counts.count(x, y, w, h);
+        let budget = match (budget, self.max_error(depth)) {
+            (budget, 0) => budget,
+            (Some(budget), max) => Some(budget.min(max)),
+            (None, max) => Some(max),
+        };
+        if changed <= budget.unwrap_or_default() {
+            changed
+        } else if side > 1 {
+            let half = side / 2;
+            let mut left_out = 0;
+            for (dx, dy) in [(0, 0), (half, 0), (0, half), (half, half)] {
+                let (x, y) = (x + dx, y + dy);
+                let budget = budget.map(|b| b - left_out);
+                left_out += self.changes_sector(shown, counts, (x, y), half, depth + 1, budget);
+            }
+            left_out
+        } else {
+            let index = y as usize * counts.resolution.stride() + x as usize / 8;
+            shown[index] ^= 0x80 >> (x % 8);
+            0
+        }
+    }
+}
+
+/// Pixels that differ between a frame and what the decoder shows.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PixelError {
+    pub wrong: u32,
+    /// Pixels in the frame
+    pub total: u32,
+}
+
+impl PixelError {
+    /// Compares two row-major buffers, ignoring the padding at the end of the rows.
+    pub fn compare(a: &[u8], b: &[u8], resolution: Resolution) -> Self {
+        let width = resolution.width() as usize;
+        // the bits past the width in the last byte of the rows are padding
+        let last_mask = 0xffu8 << ((8 - width % 8) % 8);
+        let wrong = a
+            .chunks(resolution.stride())
+            .zip(b.chunks(resolution.stride()))
+            .map(|(a, b)| {
+                let (a_last, a) = a.split_last().unwrap();
+                let (b_last, b) = b.split_last().unwrap();
+                let full: u32 = a.iter().zip(b).map(|(a, b)| (a ^ b).count_ones()).sum();
+                full + ((a_last ^ b_last) & last_mask).count_ones()
+            })
+            .sum();
+        Self {
+            wrong,
+            total: resolution.width() as u32 * resolution.height() as u32,
+        }
+    }
+
+    /// Peak signal to noise ratio in dB, infinite for an exact frame.
+    ///
+    /// With two levels the squared error of a pixel is 0 or 1, so this is the ratio of all
+    /// the pixels to the wrong ones.
+    pub fn psnr(&self) -> f64 {
+        10.0 * (self.total as f64 / self.wrong as f64).log10()
+    }
+}
+
+/// Summed-area table of the set pixels, to count them in any rectangle at once
+pub(super) struct SetCounts {
+    resolution: Resolution,
+    /// Set pixels above and to the left of each corner, `width + 1` per row
+    table: Vec<u32>,
+}
+
+impl SetCounts {
+    pub(super) fn new(buf: &[u8], resolution: Resolution) -> Self {
+        let width = resolution.width() as usize;
+        let height = resolution.height() as usize;
+        let mut table = vec![0; (width + 1) * (height + 1)];
+        for (y, row) in buf.chunks(resolution.stride()).enumerate() {
+            let mut in_row = 0;
+            for (x, bit) in row.view_bits::<Msb0>()[..width].iter().enumerate() {
+                in_row += *bit as u32;
+                table[(y + 1) * (width + 1) + x + 1] = table[y * (width + 1) + x + 1] + in_row;
+            }
+        }
+        Self { resolution, table }
+    }
+
+    /// Size of the part of a square inside the image
+    pub(super) fn clip(&self, x: u32, y: u32, side: u32) -> (u32, u32) {
+        let w = (self.resolution.width() as u32).saturating_sub(x).min(side);
+        let h = (self.resolution.height() as u32)
+            .saturating_sub(y)
+            .min(side);
+        (w, h)
+    }
+
+    pub(super) fn count(&self, x: u32, y: u32, w: u32, h: u32) -> u32 {
+        let stride = self.resolution.width() as usize + 1;
+        let at = |x: u32, y: u32| self.table[y as usize * stride + x as usize];
+        at(x + w, y + h) + at(x, y) - at(x + w, y) - at(x, y + h)
+    }
+}
@@ -1,11 +1,22 @@
-use crate::{FrameMeta, Leaf, LeafData, Position, utils::next_pos};
+use crate::{
+    FrameMeta, Leaf, LeafData, Position, Resolution,
+    entropy::{NodeModel, RangeEncoder},
+    utils::{next_pos, spans},
+};
 
 use bitvec::prelude::*;
 use std::{
-    io::{Result as IoResult, Write},
-    iter::repeat,
+    borrow::Cow,
+    cmp::min,
+    io::{Error, ErrorKind, Result as IoResult, Write},
+    iter::repeat_n,
 };
 
+#[cfg(feature = "image")]
+pub mod input;
+pub mod lossy;
+pub mod mono;
+pub mod rate;
 pub mod video;
 
 #[cfg(test)]
@@ -56,10 +67,14 @@ impl Node {
     }
 
     /// Parse a monochrome bitmap into Self.
+    ///
+    /// Sectors entirely outside of the image are left empty.
     fn from_sector(sec: Frame, use_bitmap: bool) -> Self {
-        if sec.uniform() {
+        if sec.outside() {
+            Self::Empty
+        } else if sec.uniform() {
             Self::Leaf(LeafData::Feature(sec.color()))
-        } else if sec.side > 4 || !use_bitmap {
+        } else if sec.side != 4 || !use_bitmap {
             let [tl, tr, bl, br] = sec.split_four();
             Self::Branch(Box::new([
                 Self::from_sector(tl, use_bitmap),
@@ -91,7 +106,7 @@ impl Node {
                     a[2].diff(&b[2]),
                     a[3].diff(&b[3]),
                 ];
-                if nodes.iter().eq(repeat(&Self::Empty).take(4)) {
+                if nodes.iter().eq(repeat_n(&Self::Empty, 4)) {
                     Self::Empty
                 } else {
                     Self::Branch(Box::new(nodes))
@@ -107,48 +122,468 @@ impl Node {
             _ => self.clone(),
         }
     }
+
+    /// Writes a node of `side` pixels in the compact bitmap format, see
+    /// `QuadTree::collect_compact_bitmap`.
+    fn collect_bitmap(&self, full: &Self, side: u32, out: &mut BitVecU8) {
+        match self {
+            Node::Empty => out.extend([false, false]),
+            Node::Leaf(LeafData::Feature(val)) => out.extend([true, *val]),
+            Node::Leaf(LeafData::Bitmap(bitmap)) => {
+                out.extend([false, true, true]);
+                out.extend_from_bitslice(bitmap.view_bits::<Msb0>());
+            }
+            Node::Branch(children) => {
+                out.extend([false, true]);
+                let mut branch = BitVecU8::new();
+                for (i, child) in children.iter().enumerate() {
+                    let full = full.children().map_or(child, |c| &c[i]);
+                    child.collect_bitmap(full, side / 2, &mut branch);
+                }
+                if side != 4 {
+                    out.extend_from_bitslice(&branch);
+                    return;
+                }
+                match full.as_bitmap() {
+                    Some(bitmap) if branch.len() > 16 => {
+                        out.push(true);
+                        out.extend_from_bitslice(bitmap.view_bits::<Msb0>());
+                    }
+                    _ => {
+                        out.push(false);
+                        out.extend_from_bitslice(&branch);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Fills the part of a rectangle inside the node, a square of `side` pixels at `(x, y)`,
+    /// see `QuadTree::fill_rect`.
+    fn fill(&mut self, fill: &Fill, (x, y): (u32, u32), side: u32) {
+        let (width, height) = (
+            fill.resolution.width() as u32,
+            fill.resolution.height() as u32,
+        );
+        if x >= width || y >= height {
+            return;
+        }
+        // the padding follows the closest pixel, only the part inside the image matters
+        let (x1, y1) = ((x + side).min(width), (y + side).min(height));
+        if fill.x1 <= x || fill.x0 >= x1 || fill.y1 <= y || fill.y0 >= y1 {
+            return;
+        }
+        if fill.x0 <= x && fill.x1 >= x1 && fill.y0 <= y && fill.y1 >= y1 {
+            *self = Node::Leaf(LeafData::Feature(fill.value));
+            return;
+        }
+
+        // only covered in part, so larger than a pixel
+        let half = side / 2;
+        let origins = [(x, y), (x + half, y), (x, y + half), (x + half, y + half)];
+        match self.color() {
+            Some(value) if side == 4 && fill.use_bitmap => {
+                *self = Node::Leaf(LeafData::Bitmap([0xff * value as u8; 2]))
+            }
+            Some(value) => {
+                let [a, b, c, d] = origins.map(|(x, y)| {
+                    if x >= width || y >= height {
+                        Node::Empty
+                    } else {
+                        Node::Leaf(LeafData::Feature(value))
+                    }
+                });
+                *self = Node::Branch(Box::new([a, b, c, d]));
+            }
+            None => (),
+        }
+
+        match self {
+            Node::Leaf(LeafData::Bitmap(bitmap)) => {
+                let old = u16::from_be_bytes(*bitmap);
+                let mut bits = 0u16;
+                for i in 0..16 {
+                    // padding pixels repeat the closest one of the image
+                    let px = (x + i % 4).min(width - 1);
+                    let py = (y + i / 4).min(height - 1);
+                    let value =
+                        if (fill.x0..fill.x1).contains(&px) && (fill.y0..fill.y1).contains(&py) {
+                            fill.value
+                        } else {
+                            old << ((py - y) * 4 + px - x) & 0x8000 != 0
+                        };
+                    bits |= (value as u16) << (15 - i);
+                }
+                *self = match bits {
+                    0 => Node::Leaf(LeafData::Feature(false)),
+                    u16::MAX => Node::Leaf(LeafData::Feature(true)),
+                    _ => Node::Leaf(LeafData::Bitmap(bits.to_be_bytes())),
+                };
+            }
+            Node::Branch(children) => {
+                for (child, origin) in children.iter_mut().zip(origins) {
+                    child.fill(fill, origin, half);
+                }
+                self.merge(origins, fill.resolution);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Turns a branch into a leaf if its children inside the image have the same color,
+    /// `origins` being their top left pixels.
+    fn merge(&mut self, origins: [(u32, u32); 4], resolution: Resolution) {
+        let children = match self {
+            Node::Branch(children) => children,
+            _ => return,
+        };
+        let (width, height) = (resolution.width() as u32, resolution.height() as u32);
+        let mut colors = children.iter().zip(origins).filter_map(|(c, (x, y))| {
+            if x >= width || y >= height {
+                None
+            } else {
+                Some(c.color())
+            }
+        });
+        if let Some(value) = colors.next().flatten() {
+            if colors.all(|c| c == Some(value)) {
+                *self = Node::Leaf(LeafData::Feature(value));
+            }
+        }
+    }
+
+    /// Color of a uniform node inside the image, where empty nodes are unlit.
+    fn color(&self) -> Option<bool> {
+        match self {
+            Node::Empty => Some(false),
+            Node::Leaf(LeafData::Feature(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Pixel at `(x, y)` from the top left of a node of `side` pixels.
+    fn pixel(&self, x: u32, y: u32, side: u32) -> bool {
+        match self {
+            Node::Empty => false,
+            Node::Leaf(LeafData::Feature(value)) => *value,
+            Node::Leaf(LeafData::Bitmap(bitmap)) => {
+                u16::from_be_bytes(*bitmap) << (y * 4 + x) & 0x8000 != 0
+            }
+            Node::Branch(children) => {
+                let half = side / 2;
+                let child = &children[((y >= half) as usize) << 1 | (x >= half) as usize];
+                child.pixel(x % half, y % half, half)
+            }
+        }
+    }
+
+    /// Children of the node, or the quadrants of a leaf as if it was split.
+    ///
+    /// Quadrants of bitmaps are branches of single pixels.
+    fn quadrants(&self) -> Cow<'_, [Node; 4]> {
+        match self {
+            Node::Branch(children) => Cow::Borrowed(children),
+            Node::Leaf(LeafData::Bitmap(_)) => Cow::Owned([0, 1, 2, 3].map(|q| {
+                let pixels = [0, 1, 2, 3].map(|p| {
+                    let x = (q & 1) * 2 + (p & 1);
+                    let y = (q >> 1) * 2 + (p >> 1);
+                    Node::Leaf(LeafData::Feature(self.pixel(x, y, 4)))
+                });
+                Node::Branch(Box::new(pixels))
+            })),
+            leaf => Cow::Owned([leaf.clone(), leaf.clone(), leaf.clone(), leaf.clone()]),
+        }
+    }
+
+    /// Draws the lit pixels of a node, a square of `side` pixels at `(x, y)`, into a zeroed
+    /// framebuffer, see `QuadTree::to_bitmap`.
+    fn draw(&self, buf: &mut [u8], (x, y): (u32, u32), side: u32, resolution: Resolution) {
+        let (width, height) = (resolution.width() as u32, resolution.height() as u32);
+        if x >= width || y >= height {
+            return;
+        }
+        let stride = resolution.stride();
+        match self {
+            Node::Empty | Node::Leaf(LeafData::Feature(false)) => (),
+            Node::Leaf(LeafData::Feature(true)) => {
+                let x1 = (x + side).min(width) as usize;
+                let y1 = (y + side).min(height) as usize;
+                for row in buf.chunks_exact_mut(stride).take(y1).skip(y as usize) {
+                    for (byte, lo, hi) in spans(x as usize, x1) {
+                        // most significant bit first
+                        row[byte] |= (0xff >> lo) & (0xff00u16 >> hi) as u8;
+                    }
+                }
+            }
+            Node::Leaf(LeafData::Bitmap(bitmap)) => {
+                let pixels = buf.view_bits_mut::<Msb0>();
+                for i in bitmap.view_bits::<Msb0>().iter_ones() {
+                    let (px, py) = (x as usize + i % 4, y as usize + i / 4);
+                    // padding pixels are dropped
+                    if px < width as usize && py < height as usize {
+                        pixels.set(py * stride * 8 + px, true);
+                    }
+                }
+            }
+            Node::Branch(children) => {
+                let half = side / 2;
+                let origins = [(x, y), (x + half, y), (x, y + half), (x + half, y + half)];
+                for (child, origin) in children.iter().zip(origins) {
+                    child.draw(buf, origin, half, resolution);
+                }
+            }
+        }
+    }
+
+    /// Applies `op` to the pixels of two nodes, squares of `side` pixels at `(x, y)`,
+    /// see `QuadTree::union`.
+    fn combine<F>(&self, other: &Self, op: &Combine<F>, (x, y): (u32, u32), side: u32) -> Self
+    where
+        F: Fn(bool, bool) -> bool,
+    {
+        let (width, height) = (op.resolution.width() as u32, op.resolution.height() as u32);
+        if x >= width || y >= height {
+            return Node::Empty;
+        }
+        // no need to look inside a node if the other one decides the result
+        let f = &op.op;
+        let color = match (self.color(), other.color()) {
+            (Some(a), Some(b)) => Some(f(a, b)),
+            (Some(a), None) if f(a, false) == f(a, true) => Some(f(a, false)),
+            (None, Some(b)) if f(false, b) == f(true, b) => Some(f(false, b)),
+            _ => None,
+        };
+        if let Some(value) = color {
+            return Node::Leaf(LeafData::Feature(value));
+        }
+
+        if side == 4 && op.use_bitmap {
+            let mut bits = 0u16;
+            for i in 0..16 {
+                // padding pixels repeat the closest one of the image
+                let px = (x + i % 4).min(width - 1) - x;
+                let py = (y + i / 4).min(height - 1) - y;
+                let value = f(self.pixel(px, py, side), other.pixel(px, py, side));
+                bits |= (value as u16) << (15 - i);
+            }
+            return match bits {
+                0 => Node::Leaf(LeafData::Feature(false)),
+                u16::MAX => Node::Leaf(LeafData::Feature(true)),
+                _ => Node::Leaf(LeafData::Bitmap(bits.to_be_bytes())),
+            };
+        }
+
+        let half = side / 2;
+        let origins = [(x, y), (x + half, y), (x, y + half), (x + half, y + half)];
+        let (a, b) = (self.quadrants(), other.quadrants());
+        let mut node = Node::Branch(Box::new(
+            [0, 1, 2, 3].map(|i| a[i].combine(&b[i], op, origins[i], half)),
+        ));
+        node.merge(origins, op.resolution);
+        node
+    }
+
+    /// Renders a node of 4x4 pixels as a bitmap leaf, unless parts of it are empty.
+    fn as_bitmap(&self) -> Option<[u8; 2]> {
+        let mut bitmap = [0u8; 2];
+        let bits = bitmap.view_bits_mut::<Msb0>();
+        match self {
+            Node::Empty => return None,
+            Node::Leaf(LeafData::Bitmap(b)) => return Some(*b),
+            Node::Leaf(LeafData::Feature(val)) => bits.fill(*val),
+            Node::Branch(quadrants) => {
+                for (q, quadrant) in quadrants.iter().enumerate() {
+                    for p in 0..4 {
+                        let val = match quadrant {
+                            Node::Leaf(LeafData::Feature(val)) => *val,
+                            Node::Branch(pixels) => match pixels[p] {
+                                Node::Leaf(LeafData::Feature(val)) => val,
+                                _ => return None,
+                            },
+                            _ => return None,
+                        };
+                        let x = (q & 1) * 2 + (p & 1);
+                        let y = (q >> 1) * 2 + (p >> 1);
+                        bits.set(y * 4 + x, val);
+                    }
+                }
+            }
+        }
+        Some(bitmap)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct QuadTree {
     pub head: Node,
+    /// Real dimensions of the image the tree was built from
+    pub resolution: Resolution,
+    /// Squares of 4x4 pixels that aren't uniform are bitmap leaves instead of branches,
+    /// see `from_buf`; the editing methods keep to it.
+    pub use_bitmap: bool,
 }
 
 impl QuadTree {
-    pub fn new() -> Self {
-        Self { head: Node::Empty }
+    pub fn new(resolution: Resolution) -> Self {
+        Self {
+            head: Node::Empty,
+            resolution,
+            use_bitmap: false,
+        }
     }
 
     /// Builds a new tree from a 128x64 monochrome framebuffer.
     pub fn from_128x64(buf: &[u8; 1024], use_bitmap: bool) -> Self {
-        let mut z_curve: BitVecU8 = BitVec::with_capacity(buf.len() * 8);
-        z_order_2to1(buf, &mut z_curve, 128);
+        Self::from_buf(buf, Resolution::default(), use_bitmap)
+    }
 
-        if Frame::new(&z_curve, 128).uniform() {
-            return QuadTree {
-                head: Node::Leaf(LeafData::Feature(buf[0] == 255)),
-            };
+    /// Builds a new tree from one byte per pixel grayscale, see `Binarize` for the conversion.
+    pub fn from_gray(
+        luma: &[u8],
+        resolution: Resolution,
+        binarize: mono::Binarize,
+        use_bitmap: bool,
+    ) -> Self {
+        Self::from_buf(&binarize.apply(luma, resolution), resolution, use_bitmap)
+    }
+
+    /// Builds a new tree from a row-major monochrome framebuffer of any resolution.
+    ///
+    /// Rows are packed 8 pixels per byte, most significant bit first, and padded to a whole byte.
+    /// The image is placed in the top left corner of the enclosing power-of-two square,
+    /// sectors entirely outside of it are left empty.
+    ///
+    /// Panics if the buffer length doesn't match the resolution.
+    pub fn from_buf(buf: &[u8], resolution: Resolution, use_bitmap: bool) -> Self {
+        assert_eq!(
+            buf.len(),
+            resolution.buf_len(),
+            "Buffer length doesn't match the resolution"
+        );
+
+        let side = resolution.side() as usize;
+        let mut z_curve: BitVecU8 = BitVec::with_capacity(side * side);
+        z_order(buf, &mut z_curve, resolution, side, 0, 0);
+
+        let head = Node::from_sector(Frame::new(&z_curve, side, resolution), use_bitmap);
+        Self {
+            head,
+            resolution,
+            use_bitmap,
+        }
+    }
+
+    /// Returns `false` outside of the image, and in empty nodes.
+    pub fn get_pixel(&self, x: u32, y: u32) -> bool {
+        if x >= self.resolution.width() as u32 || y >= self.resolution.height() as u32 {
+            return false;
         }
+        self.head.pixel(x, y, self.resolution.side())
+    }
 
-        let mut out = Self {
-            head: Node::empty_branch(),
+    /// Pixels outside of the image are ignored.
+    pub fn set_pixel(&mut self, x: u32, y: u32, value: bool) {
+        self.fill_rect(x, y, 1, 1, value);
+    }
+
+    /// Fills a rectangle, clipped to the image.
+    ///
+    /// Nodes are split and merged so the tree stays the one `from_buf` builds from the edited
+    /// image. Empty nodes inside the image, as in trees from `new`, count as unlit.
+    pub fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, value: bool) {
+        let (image_w, image_h) = (
+            self.resolution.width() as u32,
+            self.resolution.height() as u32,
+        );
+        let fill = Fill {
+            x0: x,
+            y0: y,
+            x1: x.saturating_add(width).min(image_w),
+            y1: y.saturating_add(height).min(image_h),
+            value,
+            resolution: self.resolution,
+            use_bitmap: self.use_bitmap,
         };
-        let [left, right, _, _] = out.head.children_mut().unwrap();
+        if fill.x0 < fill.x1 && fill.y0 < fill.y1 {
+            self.head.fill(&fill, (0, 0), self.resolution.side());
+        }
+    }
 
-        *left = Node::from_sector(Frame::new(&z_curve[..4096], 64), use_bitmap);
-        *right = Node::from_sector(Frame::new(&z_curve[4096..], 64), use_bitmap);
+    /// Fills the whole image.
+    pub fn clear(&mut self, value: bool) {
+        self.head = Node::Leaf(LeafData::Feature(value));
+    }
 
-        out
+    /// Renders the tree into a row-major framebuffer, the layout `from_buf` reads.
+    ///
+    /// Empty nodes are left unlit, as is the padding at the end of the rows.
+    pub fn to_bitmap(&self) -> Vec<u8> {
+        let mut buf = vec![0; self.resolution.buf_len()];
+        let side = self.resolution.side();
+        self.head.draw(&mut buf, (0, 0), side, self.resolution);
+        buf
     }
 
-    pub fn leaves(&self) -> QuadTreeIterator {
+    /// Pixels lit in either tree.
+    ///
+    /// Like the other set operations, it walks both trees at once without looking inside
+    /// nodes the result doesn't depend on, and returns the tree `from_buf` would build from
+    /// the result, with bitmap leaves if `self.use_bitmap` is set.
+    /// Empty nodes inside the image count as unlit.
+    ///
+    /// Panics if the resolutions don't match.
+    pub fn union(&self, other: &Self) -> Self {
+        self.combine(other, |a, b| a | b)
+    }
+
+    /// Pixels lit in both trees.
+    pub fn intersection(&self, other: &Self) -> Self {
+        self.combine(other, |a, b| a & b)
+    }
+
+    /// Pixels lit in `self` but not in `other`.
+    pub fn difference(&self, other: &Self) -> Self {
+        self.combine(other, |a, b| a & !b)
+    }
+
+    /// Pixels lit in only one of the trees.
+    pub fn xor(&self, other: &Self) -> Self {
+        self.combine(other, |a, b| a ^ b)
+    }
+
+    /// Every pixel of the image flipped.
+    pub fn invert(&self) -> Self {
+        self.combine(self, |a, _| !a)
+    }
+
+    fn combine<F: Fn(bool, bool) -> bool>(&self, other: &Self, op: F) -> Self {
+        assert_eq!(
+            self.resolution, other.resolution,
+            "Trees of different resolutions"
+        );
+        let op = Combine {
+            op,
+            resolution: self.resolution,
+            use_bitmap: self.use_bitmap,
+        };
+        Self {
+            head: self
+                .head
+                .combine(&other.head, &op, (0, 0), self.resolution.side()),
+            resolution: self.resolution,
+            use_bitmap: self.use_bitmap,
+        }
+    }
+
+    pub fn leaves(&self) -> QuadTreeIterator<'_> {
         QuadTreeIterator {
             inner: self.nodes(),
             position: Default::default(),
+            root: self.resolution.depth(),
         }
     }
 
-    pub fn nodes(&self) -> QuadTreeTraverser {
+    pub fn nodes(&self) -> QuadTreeTraverser<'_> {
         QuadTreeTraverser {
             stack: vec![&self.head],
         }
@@ -157,6 +592,8 @@ impl QuadTree {
     pub fn diff(&self, other: &Self) -> Self {
         Self {
             head: self.head.diff(&other.head),
+            resolution: self.resolution,
+            use_bitmap: self.use_bitmap,
         }
     }
 
@@ -182,7 +619,19 @@ impl QuadTree {
     /// It otherwise takes up two bytes.
     ///
     /// When depth is more than 5, the 4x4 bitmap is stored to save space.
+    ///
+    /// Depth 6 marks bitmap leaves, so the format only fits trees up to 7 levels deep,
+    /// images up to 128 pixels per side built with bitmaps.
+    /// Deeper trees fail with `ErrorKind::InvalidInput` before anything is written.
+    /// So do leaves smaller than 4x4 pixels, as edited or combined trees without bitmaps
+    /// can have, when they are reached.
     pub fn store_packed<W: Write>(&self, mut w: W) -> IoResult<usize> {
+        if self.resolution.depth() > 7 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The packed format only fits images up to 128 pixels per side",
+            ));
+        }
         let yes = self.leaves().filter(|l| l.feat_or_data(true));
         let no = self.leaves().filter(|l| l.feat_or_data(false));
 
@@ -215,6 +664,46 @@ impl QuadTree {
         Ok(out)
     }
 
+    /// Range codes the output of `collect_compact`, see the `entropy` module for the model.
+    ///
+    /// Each frame starts with a fresh model and ends with the flushed coder state,
+    /// so frames can still be decoded independently.
+    pub fn collect_compact_entropy(&self) -> Result<Vec<u8>, &str> {
+        let compact = self.collect_compact()?;
+        let mut rc = RangeEncoder::default();
+        let mut model = NodeModel::default();
+        let mut pos = Position::new();
+
+        for code in compact.chunks_exact(2).map(|c| c.load_be::<u8>()) {
+            model.encode(&mut rc, &pos, code);
+            if code == 0b01 {
+                pos.push(0).unwrap();
+            } else {
+                next_pos(&mut pos);
+                if pos.is_empty() {
+                    break;
+                }
+            }
+        }
+        Ok(rc.finish())
+    }
+
+    /// Like `collect_compact`, with an escape for 4x4 bitmap leaves.
+    ///
+    /// Branches covering 4x4 pixels are followed by an extra bit:
+    /// `0` means the branch goes on as usual,
+    /// `1` means it's a bitmap leaf and the next 16 bits are its pixels, row by row.
+    /// Every such branch gets whichever representation is smaller.
+    ///
+    /// `full` is the tree `self` was diffed against the previous frame from,
+    /// bitmaps take the pixels that didn't change from it. Pass `self` for full frames.
+    pub fn collect_compact_bitmap(&self, full: &Self) -> BitVecU8 {
+        let mut out = BitVecU8::new();
+        self.head
+            .collect_bitmap(&full.head, self.resolution.side(), &mut out);
+        out
+    }
+
     /// Same as `store_packed`, but keeps both features and tells the decoder not to clear the
     /// framebuffer with the inactive feature before drawing, used in p-frames.
     pub fn store_as_diff<W: Write>(&self, mut w: W) -> IoResult<(usize, usize)> {
@@ -232,6 +721,26 @@ impl QuadTree {
 
         Ok((count_yes, count_no))
     }
+
+    /// Stores a tree built from the XOR of two frames as a single p-frame record,
+    /// its lit leaves toggle the pixels of the previous frame, see `FrameMeta::xor`.
+    ///
+    /// Unlike `store_as_diff`, it needs one header and no leaves for the pixels that didn't
+    /// change.
+    pub fn store_as_xor<W: Write>(&self, mut w: W) -> IoResult<usize> {
+        let mut count = 1;
+
+        let meta = FrameMeta {
+            xor: true,
+            ..FrameMeta::new(true, true, true)
+        };
+        w.write_all(&[meta.into()])?;
+        for leaf in self.leaves().filter(|l| l.feat_or_data(true)) {
+            count += leaf.write(&mut w)?;
+        }
+
+        Ok(count)
+    }
 }
 
 /// Depth-first traversal returning every node
@@ -243,11 +752,10 @@ pub struct QuadTreeTraverser<'a> {
 impl<'a> Iterator for QuadTreeTraverser<'a> {
     type Item = &'a Node;
     fn next(&mut self) -> Option<Self::Item> {
-        self.stack.pop().map(|node| {
+        self.stack.pop().inspect(|node| {
             if let Some(nodes) = node.children() {
-                self.stack.extend(nodes.into_iter().rev());
+                self.stack.extend(nodes.iter().rev());
             }
-            node
         })
     }
 }
@@ -257,21 +765,19 @@ impl<'a> Iterator for QuadTreeTraverser<'a> {
 pub struct QuadTreeIterator<'a> {
     inner: QuadTreeTraverser<'a>,
     position: Position,
+    root: u8,
 }
 
 impl Iterator for QuadTreeIterator<'_> {
     type Item = Leaf;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(node) = self.inner.next() {
+        for node in self.inner.by_ref() {
             match node {
                 Node::Leaf(data) => {
                     let pos = self.position.clone();
                     next_pos(&mut self.position);
-                    return Some(Leaf::new(
-                        *data,
-                        pos.clone(),
-                    ));
+                    return Some(Leaf::new(*data, pos.clone(), self.root));
                 }
                 Node::Empty => next_pos(&mut self.position).unwrap(),
                 Node::Branch(_) => self.position.push(0).expect("Max depth exceeded"),
@@ -284,6 +790,12 @@ impl Iterator for QuadTreeIterator<'_> {
 impl Leaf {
     fn write<W: std::io::Write>(&self, mut w: W) -> IoResult<usize> {
         let depth = self.pos.len();
+        if depth > 5 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The packed format has no leaves smaller than 4x4 pixels, use bitmaps",
+            ));
+        }
         let mut data = [0u8; 2];
         let bits = data.view_bits_mut::<Msb0>();
 
@@ -298,9 +810,6 @@ impl Leaf {
             bits[4..][i * 2..=i * 2 + 1].store(*p)
         }
 
-        if depth > 5 {
-            panic!("Depth not supposed to exceed 5")
-        }
         let mut written = 0;
         if depth > 2 {
             w.write_all(&data)?;
@@ -360,16 +869,49 @@ fn compare_bits(buf: &BitSliceU8) -> bool {
     true
 }
 
+/// Rectangle filled by `QuadTree::fill_rect`, clipped to the image
+struct Fill {
+    x0: u32,
+    y0: u32,
+    /// Exclusive
+    x1: u32,
+    y1: u32,
+    value: bool,
+    resolution: Resolution,
+    use_bitmap: bool,
+}
+
+/// Pixelwise operation of `QuadTree::combine`
+struct Combine<F> {
+    op: F,
+    resolution: Resolution,
+    use_bitmap: bool,
+}
+
 /// A wrapper for a slice representing a square
 struct Frame<'a> {
     side: usize,
+    /// Coordinates of the top left corner in the image
+    origin: (usize, usize),
+    resolution: Resolution,
     buf: &'a BitSliceU8,
 }
 
 impl<'a> Frame<'a> {
     /// Assumes buffer is z-ordered
-    pub fn new(buf: &'a BitSliceU8, side: usize) -> Self {
-        Self { side, buf }
+    pub fn new(buf: &'a BitSliceU8, side: usize, resolution: Resolution) -> Self {
+        Self {
+            side,
+            origin: (0, 0),
+            resolution,
+            buf,
+        }
+    }
+
+    /// Checks if the square only covers padding
+    pub fn outside(&self) -> bool {
+        self.origin.0 >= self.resolution.width() as usize
+            || self.origin.1 >= self.resolution.height() as usize
     }
 
     /// Checks if all the bits in the buffer are set or unset
@@ -386,31 +928,44 @@ impl<'a> Frame<'a> {
     pub fn split_four(self) -> [Frame<'a>; 4] {
         let len = self.buf.len() / 4;
         let side = self.side / 2;
+        let (x, y) = self.origin;
+        let sub = |i: usize, origin| Frame {
+            side,
+            origin,
+            resolution: self.resolution,
+            buf: &self.buf[i * len..(i + 1) * len],
+        };
 
         [
-            Frame::new(&self.buf[..len], side),
-            Frame::new(&self.buf[len..2 * len], side),
-            Frame::new(&self.buf[2 * len..3 * len], side),
-            Frame::new(&self.buf[3 * len..4 * len], side),
+            sub(0, (x, y)),
+            sub(1, (x + side, y)),
+            sub(2, (x, y + side)),
+            sub(3, (x + side, y + side)),
         ]
     }
 }
 
-// calls z_order on the two top squares
-fn z_order_2to1(source: &[u8], dest: &mut BitVecU8, mut width: usize) {
-    width /= 2;
-    z_order(source, dest, width, 0, 0);
-    z_order(source, dest, width, width, 0);
-}
-
-fn z_order(source: &[u8], dest: &mut BitVecU8, mut width: usize, x: usize, y: usize) {
+/// Reorders a square of the image along the z-curve.
+///
+/// Coordinates past the edges are clamped, so the padding repeats the closest pixel and a
+/// sector is uniform exactly when the part of the image it covers is.
+fn z_order(
+    source: &[u8],
+    dest: &mut BitVecU8,
+    res: Resolution,
+    mut width: usize,
+    x: usize,
+    y: usize,
+) {
     if width == 1 {
-        dest.push(source.view_bits::<Msb0>()[x + y * 128])
+        let x = min(x, res.width() as usize - 1);
+        let y = min(y, res.height() as usize - 1);
+        dest.push(source.view_bits::<Msb0>()[x + y * res.stride() * 8])
     } else {
         width /= 2;
-        z_order(source, dest, width, x, y);
-        z_order(source, dest, width, x + width, y);
-        z_order(source, dest, width, x, y + width);
-        z_order(source, dest, width, x + width, y + width);
+        z_order(source, dest, res, width, x, y);
+        z_order(source, dest, res, width, x + width, y);
+        z_order(source, dest, res, width, x, y + width);
+        z_order(source, dest, res, width, x + width, y + width);
     }
 }
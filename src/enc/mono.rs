@@ -0,0 +1,134 @@
+use crate::Resolution;
+
+/// How grayscale pixels are turned into monochrome ones.
+///
+/// The choice matters as much for the size of the trees as for the looks: quadtrees store
+/// uniform squares for free, while every isolated pixel costs a branch all the way down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binarize {
+    /// Pixels at least as bright as the threshold are set.
+    ///
+    /// Flat areas stay flat, compresses best and keeps P-frames small.
+    Threshold(u8),
+    /// Fixed threshold picked for each image with Otsu's method.
+    ///
+    /// Compresses like `Threshold`, but the threshold can jump between frames of a video,
+    /// making whole areas flicker.
+    Otsu,
+    /// Ordered dithering with a 4x4 Bayer matrix.
+    ///
+    /// Gradients turn into regular patterns that split the tree down to 4x4 squares or single
+    /// pixels, bitmap leaves help. The pattern is fixed to the screen, so still areas stay still
+    /// between frames.
+    Bayer,
+    /// Floyd–Steinberg error diffusion.
+    ///
+    /// Looks best on photos, but the noise-like patterns compress worst of all
+    /// and change all over the frame when any pixel changes, ruining P-frames.
+    FloydSteinberg,
+}
+
+impl Default for Binarize {
+    fn default() -> Self {
+        Self::Threshold(0x80)
+    }
+}
+
+#[rustfmt::skip]
+const BAYER: [[u8; 4]; 4] = [
+    [ 0,  8,  2, 10],
+    [12,  4, 14,  6],
+    [ 3, 11,  1,  9],
+    [15,  7, 13,  5],
+];
+
+impl Binarize {
+    /// Converts one byte per pixel grayscale into a row-major, MSB-first buffer as taken by
+    /// `QuadTree::from_buf`.
+    pub fn apply(self, luma: &[u8], resolution: Resolution) -> Vec<u8> {
+        let width = resolution.width() as usize;
+        let height = resolution.height() as usize;
+        assert_eq!(luma.len(), width * height);
+
+        let mut buf = vec![0; resolution.buf_len()];
+        let mut set = |x: usize, y: usize| buf[y * resolution.stride() + x / 8] |= 0x80 >> (x % 8);
+
+        match self {
+            Self::Threshold(_) | Self::Otsu => {
+                let threshold = match self {
+                    Self::Threshold(t) => t,
+                    _ => otsu_threshold(luma),
+                };
+                for (i, &l) in luma.iter().enumerate() {
+                    if l >= threshold {
+                        set(i % width, i / width);
+                    }
+                }
+            }
+            Self::Bayer => {
+                for (i, &l) in luma.iter().enumerate() {
+                    let (x, y) = (i % width, i / width);
+                    if l as u16 >= BAYER[y % 4][x % 4] as u16 * 16 + 8 {
+                        set(x, y);
+                    }
+                }
+            }
+            Self::FloydSteinberg => {
+                // errors carried to the current and the next row, with a pixel of margin
+                let mut errors = [vec![0i16; width + 2], vec![0i16; width + 2]];
+                for (y, row) in luma.chunks_exact(width).enumerate() {
+                    for (x, &l) in row.iter().enumerate() {
+                        let value = l as i16 + errors[0][x + 1] / 16;
+                        let error = if value >= 0x80 {
+                            set(x, y);
+                            value - 0xff
+                        } else {
+                            value
+                        };
+                        errors[0][x + 2] += error * 7;
+                        errors[1][x] += error * 3;
+                        errors[1][x + 1] += error * 5;
+                        errors[1][x + 2] += error;
+                    }
+                    errors.swap(0, 1);
+                    errors[1].iter_mut().for_each(|e| *e = 0);
+                }
+            }
+        }
+        buf
+    }
+}
+
+/// Threshold that best separates the histogram of the image in two classes.
+pub fn otsu_threshold(luma: &[u8]) -> u8 {
+    let mut histogram = [0u64; 256];
+    for &l in luma {
+        histogram[l as usize] += 1;
+    }
+    let total = luma.len() as f64;
+    let sum: f64 = histogram
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| i as f64 * c as f64)
+        .sum();
+
+    let (mut best, mut best_variance) = (0, 0.0);
+    let (mut weight_bg, mut sum_bg) = (0.0, 0.0);
+    for (i, &count) in histogram.iter().enumerate() {
+        weight_bg += count as f64;
+        sum_bg += i as f64 * count as f64;
+        let weight_fg = total - weight_bg;
+        if weight_bg == 0.0 || weight_fg == 0.0 {
+            continue;
+        }
+        let mean_bg = sum_bg / weight_bg;
+        let mean_fg = (sum - sum_bg) / weight_fg;
+        let variance = weight_bg * weight_fg * (mean_bg - mean_fg).powi(2);
+        if variance > best_variance {
+            best = i;
+            best_variance = variance;
+        }
+    }
+    // pixels above the background class are set
+    (best + 1).min(0xff) as u8
+}
@@ -0,0 +1,73 @@
+/// Size limit `VideoEncoder::set_rate_limit` keeps the frames under.
+///
+/// Sizes count every byte of a frame, with the lengths of its records and its frame header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimit {
+    /// Every frame takes at most this many bytes
+    Frame(usize),
+    /// Frames take `bytes_per_second / frame_rate` bytes on average.
+    ///
+    /// Bytes left unused by small frames can be spent by the next ones, up to a second worth
+    /// of them, and frames over their budget take it out of the next ones.
+    Average { bytes_per_second: u32, frame_rate: u16 },
+}
+
+/// Budget of the frames under a `RateLimit`
+#[derive(Debug, Clone)]
+pub(super) struct RateControl {
+    limit: RateLimit,
+    /// Unspent bytes, scaled by the frame rate so that the budget of a frame is exact
+    credit: i64,
+}
+
+impl RateControl {
+    pub(super) fn new(limit: RateLimit) -> Self {
+        Self { limit, credit: 0 }
+    }
+
+    /// Bytes the next frame can take
+    pub(super) fn budget(&mut self) -> usize {
+        match self.limit {
+            RateLimit::Frame(max) => max,
+            RateLimit::Average {
+                bytes_per_second,
+                frame_rate,
+            } => {
+                let second = bytes_per_second as i64 * frame_rate.max(1) as i64;
+                self.credit = (self.credit + bytes_per_second as i64).min(second);
+                (self.credit / frame_rate.max(1) as i64).max(0) as usize
+            }
+        }
+    }
+
+    /// Takes a frame of `size` bytes out of the budget.
+    pub(super) fn spend(&mut self, size: usize) {
+        if let RateLimit::Average { frame_rate, .. } = self.limit {
+            self.credit -= size as i64 * frame_rate.max(1) as i64;
+        }
+    }
+}
+
+/// How a frame was fit into the budget of a `RateLimit`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RateReport {
+    /// Bytes the frame could take
+    pub budget: usize,
+    /// Bytes the frame took, more than `budget` if it couldn't be made to fit
+    pub size: usize,
+    /// Allowance of `Lossy::global` the frame lost pixels with to fit, 0 if it didn't
+    pub level: u32,
+    /// The frame was due to be an I-frame and was sent as a P-frame to fit
+    pub postponed_i_frame: bool,
+}
+
+impl RateReport {
+    /// Whether the frame lost quality or an I-frame to fit
+    pub fn degraded(&self) -> bool {
+        self.level > 0 || self.postponed_i_frame
+    }
+
+    pub fn over_budget(&self) -> bool {
+        self.size > self.budget
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::unusual_byte_groupings)]
+
 use super::*;
 
 const M: u8 = u8::MAX;
@@ -76,22 +78,27 @@ fn expected_leaves() -> [Leaf; 5] {
         Leaf::new(
             LeafData::Feature(true),
             heapless::Vec::from_slice(&[1, 1]).unwrap(),
+            7,
         ),
         Leaf::new(
             LeafData::Feature(true),
             heapless::Vec::from_slice(&[1, 3, 1]).unwrap(),
+            7,
         ),
         Leaf::new(
             LeafData::Feature(true),
             heapless::Vec::from_slice(&[1, 3, 3, 1]).unwrap(),
+            7,
         ),
         Leaf::new(
             LeafData::Feature(true),
             heapless::Vec::from_slice(&[1, 3, 3, 3, 1]).unwrap(),
+            7,
         ),
         Leaf::new(
             LeafData::Bitmap([0b0011_0011, 0b0001_0000]),
             heapless::Vec::from_slice(&[1, 3, 3, 3, 3]).unwrap(),
+            7,
         ),
     ]
 }
@@ -129,7 +136,8 @@ fn full() {
         tree.leaves().collect::<Vec<_>>(),
         [Leaf::new(
             LeafData::Feature(true),
-            heapless::Vec::from_slice(&[]).unwrap()
+            heapless::Vec::from_slice(&[]).unwrap(),
+            7,
         )]
     );
     assert_eq!(
@@ -155,7 +163,8 @@ fn empty() {
         tree.leaves().collect::<Vec<_>>(),
         [Leaf::new(
             LeafData::Feature(false),
-            heapless::Vec::from_slice(&[]).unwrap()
+            heapless::Vec::from_slice(&[]).unwrap(),
+            7,
         )]
     );
     assert_eq!(
@@ -204,6 +213,8 @@ fn traverse() {
             Node::Leaf(LeafData::Feature(true)),
             Node::Leaf(LeafData::Feature(false)),
         ])),
+        resolution: Default::default(),
+        use_bitmap: false,
     };
     let nodes: Vec<_> = tree.nodes().collect();
 
@@ -237,6 +248,8 @@ fn diff_same() {
             Node::Leaf(LeafData::Feature(true)),
             Node::Leaf(LeafData::Feature(false)),
         ])),
+        resolution: Default::default(),
+        use_bitmap: false,
     };
     let diff = tree.diff(&tree);
 
@@ -252,6 +265,8 @@ fn diff() {
             Node::Leaf(LeafData::Feature(true)),
             Node::Leaf(LeafData::Feature(true)),
         ])),
+        resolution: Default::default(),
+        use_bitmap: false,
     };
     let new = QuadTree {
         head: Node::Branch(Box::new([
@@ -265,6 +280,8 @@ fn diff() {
             Node::Leaf(LeafData::Feature(true)),
             Node::Leaf(LeafData::Feature(false)),
         ])),
+        resolution: Default::default(),
+        use_bitmap: false,
     };
 
     let diff = new.diff(&old);
@@ -283,3 +300,409 @@ fn diff() {
 
     assert_eq!(diff.head, expected);
 }
+
+#[test]
+fn arbitrary_resolution() {
+    // 96x96, top left 64x64 square set
+    let res = Resolution::new(96, 96).unwrap();
+    let mut buf = vec![0; res.buf_len()];
+    for row in buf.chunks_mut(res.stride()).take(64) {
+        row[..8].fill(u8::MAX);
+    }
+
+    let tree = QuadTree::from_buf(&buf, res, true);
+    assert_eq!(res.depth(), 7);
+    assert_eq!(
+        tree.head,
+        Node::Branch(Box::new([
+            Node::Leaf(LeafData::Feature(true)),
+            Node::Leaf(LeafData::Feature(false)),
+            Node::Leaf(LeafData::Feature(false)),
+            Node::Leaf(LeafData::Feature(false)),
+        ]))
+    );
+}
+
+#[test]
+fn padding_left_empty() {
+    // 256x64, one pixel set in the top left corner
+    let res = Resolution::new(256, 64).unwrap();
+    let mut buf = vec![0; res.buf_len()];
+    buf[0] = 0x80;
+
+    let tree = QuadTree::from_buf(&buf, res, false);
+    assert_eq!(res.depth(), 8);
+
+    let [_, tr, bl, br] = tree.head.children().unwrap();
+    assert_eq!(tr, &Node::Leaf(LeafData::Feature(false)));
+    assert_eq!(bl, &Node::Empty);
+    assert_eq!(br, &Node::Empty);
+
+    let leaves: Vec<_> = tree.leaves().filter(|l| l.feat_or_data(true)).collect();
+    assert_eq!(
+        leaves,
+        [Leaf::new(
+            LeafData::Feature(true),
+            heapless::Vec::from_slice(&[0; 8]).unwrap(),
+            8,
+        )]
+    );
+}
+
+#[test]
+fn tiny() {
+    let res = Resolution::new(2, 2).unwrap();
+    let tree = QuadTree::from_buf(&[0b1000_0000, 0b0100_0000], res, true);
+
+    assert_eq!(
+        tree.head,
+        Node::Branch(Box::new([
+            Node::Leaf(LeafData::Feature(true)),
+            Node::Leaf(LeafData::Feature(false)),
+            Node::Leaf(LeafData::Feature(false)),
+            Node::Leaf(LeafData::Feature(true)),
+        ]))
+    );
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn image_input() {
+    use super::input::{detect, load_image, InputError};
+
+    let res = Resolution::new(10, 2).unwrap();
+    let expected = [0b1010_1010, 0b1000_0000, 0, 0b0100_0000];
+
+    // PBM pixels are black when set
+    let pbm = b"P1\n10 2\n0 1 0 1 0 1 0 1 0 1\n1 1 1 1 1 1 1 1 1 0\n";
+    let format = detect(pbm).unwrap();
+    assert_eq!(
+        load_image(pbm, format, res, Default::default()).unwrap(),
+        expected
+    );
+
+    let mut pgm = b"P5 10 2 255\n".to_vec();
+    pgm.extend([200, 0, 130, 0, 255, 0, 128, 0, 255, 127]);
+    pgm.extend([0, 0, 0, 0, 0, 0, 0, 0, 0, 255]);
+    let format = detect(&pgm).unwrap();
+    assert_eq!(
+        load_image(&pgm, format, res, Default::default()).unwrap(),
+        expected
+    );
+
+    assert!(matches!(
+        load_image(
+            pbm,
+            format,
+            Resolution::new(10, 3).unwrap(),
+            Default::default()
+        ),
+        Err(InputError::SizeMismatch { found: (10, 2), .. })
+    ));
+    assert!(detect(&BUF).is_none());
+}
+
+#[test]
+fn binarize() {
+    use super::mono::{otsu_threshold, Binarize};
+
+    let res = Resolution::new(16, 16).unwrap();
+    let methods = [
+        Binarize::Threshold(0x80),
+        Binarize::Otsu,
+        Binarize::Bayer,
+        Binarize::FloydSteinberg,
+    ];
+    let count = |buf: &[u8]| buf.iter().map(|b| b.count_ones()).sum::<u32>();
+
+    // flat areas stay flat whatever the method
+    let halves: Vec<u8> = (0..256).map(|i| if i < 128 { 0 } else { 0xff }).collect();
+    for method in methods {
+        let buf = method.apply(&halves, res);
+        assert_eq!(buf, [[0; 16], [0xff; 16]].concat(), "{:?}", method);
+    }
+
+    // dithering keeps the average brightness
+    let gray = [0x40; 256];
+    assert_eq!(count(&Binarize::Threshold(0x80).apply(&gray, res)), 0);
+    assert_eq!(count(&Binarize::Bayer.apply(&gray, res)), 64);
+    let fs = count(&Binarize::FloydSteinberg.apply(&gray, res));
+    assert!((56..=72).contains(&fs), "{}", fs);
+
+    let bimodal: Vec<u8> = (0..256).map(|i| if i % 3 == 0 { 40 } else { 90 }).collect();
+    let threshold = otsu_threshold(&bimodal);
+    assert!((41..=90).contains(&threshold));
+    assert_eq!(count(&Binarize::Otsu.apply(&bimodal, res)), 170);
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn sequence_input() {
+    use super::input::{detect_sequence, Fit, Frames, InputError, SequenceFormat};
+    use image::{codecs::gif::GifEncoder, Delay, Frame, RgbaImage};
+
+    let res = Resolution::new(16, 8).unwrap();
+    let mut gif = Vec::new();
+    {
+        let mut enc = GifEncoder::new(&mut gif);
+        for lit in [0..8, 8..16] {
+            let image = RgbaImage::from_fn(16, 8, |x, _| {
+                let l = if lit.contains(&x) { 0xff } else { 0 };
+                [l, l, l, 0xff].into()
+            });
+            let delay = Delay::from_numer_denom_ms(100, 1);
+            enc.encode_frame(Frame::from_parts(image, 0, 0, delay))
+                .unwrap();
+        }
+    }
+    let format = detect_sequence(&gif).unwrap();
+    assert_eq!(format, SequenceFormat::Gif);
+    let frames = Frames::new(&gif[..], format, res, Fit::Scale, Default::default()).unwrap();
+    assert_eq!(frames.frame_rate(), Some(10));
+    let frames: Vec<_> = frames.map(Result::unwrap).collect();
+    assert_eq!(frames, [[0xff, 0].repeat(8), [0, 0xff].repeat(8)]);
+
+    // only the luma plane is read, 4:2:0 chroma is skipped
+    let mut y4m = b"YUV4MPEG2 W8 H2 F25:1 Ip A1:1 C420jpeg\n".to_vec();
+    for luma in [0x20, 0xe0] {
+        y4m.extend(b"FRAME\n");
+        y4m.extend([luma; 16]);
+        y4m.extend([0x80; 8]);
+    }
+    let format = detect_sequence(&y4m).unwrap();
+    assert_eq!(format, SequenceFormat::Y4m);
+
+    let res = Resolution::new(4, 4).unwrap();
+    let frames = Frames::new(&y4m[..], format, res, Fit::Crop, Default::default()).unwrap();
+    assert_eq!(frames.frame_rate(), Some(25));
+    let frames: Vec<_> = frames.map(Result::unwrap).collect();
+    assert_eq!(frames, [[0; 4], [0, 0xf0, 0xf0, 0]]);
+
+    let frames = Frames::new(&y4m[..], format, res, Fit::Scale, Default::default()).unwrap();
+    let frames: Vec<_> = frames.map(Result::unwrap).collect();
+    assert_eq!(frames, [[0; 4], [0xf0; 4]]);
+
+    let truncated = &y4m[..y4m.len() - 1];
+    let frames = Frames::new(truncated, format, res, Fit::Scale, Default::default()).unwrap();
+    assert!(frames.last().unwrap().is_err());
+    assert!(detect_sequence(&BUF).is_none());
+
+    // 10 bit samples take two bytes each
+    let deep = b"YUV4MPEG2 W8 H2 F25:1 C420p10\n";
+    assert!(matches!(
+        Frames::new(&deep[..], format, res, Fit::Crop, Default::default()),
+        Err(InputError::InvalidY4m("unsupported colorspace"))
+    ));
+    let fast = b"YUV4MPEG2 W8 H2 F4294967295:2 C420mpeg2\n";
+    let frames = Frames::new(&fast[..], format, res, Fit::Crop, Default::default()).unwrap();
+    assert_eq!(frames.frame_rate(), None);
+}
+
+#[test]
+fn lossy() {
+    use super::lossy::{Lossy, PixelError};
+
+    let res = Resolution::new(16, 16).unwrap();
+    let mut speck = [0; 32];
+    speck[5] = 0b0001_0000;
+
+    let mut buf = speck;
+    let error = Lossy::default().apply(&mut buf, res);
+    assert_eq!((error.wrong, error.total), (0, 256));
+    assert_eq!(buf, speck);
+    assert_eq!(error.psnr(), f64::INFINITY);
+
+    // one stray pixel collapses the whole image
+    let error = Lossy::global(1).apply(&mut buf, res);
+    assert_eq!(buf, [0; 32]);
+    assert_eq!(error, PixelError::compare(&buf, &speck, res));
+    assert_eq!(error.wrong, 1);
+    assert!((error.psnr() - 24.08).abs() < 0.01);
+    assert_eq!(
+        QuadTree::from_buf(&buf, res, false).head,
+        Node::Leaf(LeafData::Feature(false))
+    );
+
+    // only allowed in the 4x4 squares
+    let mut buf = [0xff, 0x00].repeat(16);
+    buf[4] = 0b0111_1111;
+    let error = Lossy::per_depth(&[0, 0, 1]).apply(&mut buf, res);
+    assert_eq!(error.wrong, 1);
+    assert_eq!(buf, [0xff, 0x00].repeat(16));
+    buf[4] = 0b0111_1111;
+    let error = Lossy::per_depth(&[1]).apply(&mut buf, res);
+    assert_eq!(error.wrong, 0);
+
+    // the padding isn't counted, nor overwritten
+    let res = Resolution::new(10, 3).unwrap();
+    let mut buf = [0xff, 0xc0, 0xff, 0x40, 0xff, 0xff];
+    let error = Lossy::global(1).apply(&mut buf, res);
+    assert_eq!(
+        error,
+        PixelError {
+            wrong: 1,
+            total: 30
+        }
+    );
+    assert_eq!(buf, [0xff, 0xc0, 0xff, 0xc0, 0xff, 0xff]);
+
+    // small changes are left out until they add up
+    let mut shown = [0; 6];
+    let mut frame = [0x80, 0, 0, 0, 0, 0];
+    let error = Lossy::global(1).apply_changes(&mut shown, &frame, res);
+    assert_eq!(error.wrong, 1);
+    assert_eq!(shown, [0; 6]);
+    frame[0] = 0xc0;
+    let error = Lossy::global(1).apply_changes(&mut shown, &frame, res);
+    assert_eq!(error.wrong, 1);
+    assert_eq!(shown, [0x40, 0, 0, 0, 0, 0]);
+    let error = Lossy::default().apply_changes(&mut shown, &frame, res);
+    assert_eq!(error.wrong, 0);
+    assert_eq!(shown, frame);
+}
+
+/// Applies the same edits to a tree and to a framebuffer, and checks the tree against the one
+/// built from the framebuffer.
+fn edit_and_compare(res: Resolution, use_bitmap: bool) {
+    let mut buf = vec![0u8; res.buf_len()];
+    let mut tree = QuadTree::new(res);
+    tree.use_bitmap = use_bitmap;
+    let (w, h) = (res.width() as u32, res.height() as u32);
+    let set = |buf: &mut [u8], x: u32, y: u32, value| {
+        let bits = buf.view_bits_mut::<Msb0>();
+        bits.set(y as usize * res.stride() * 8 + x as usize, value);
+    };
+
+    let rects = [
+        (0, 0, w, h, true),
+        (3, 2, 20, 9, false),
+        (w / 2, 0, w, h / 3 + 1, false),
+        (5, 5, 1, 1, true),
+        (1, h - 1, 7, 40, true),
+        (w / 4, h / 4, w / 2, h / 2, false),
+        (w / 4 + 1, h / 4 + 3, 2, 5, true),
+        (w.saturating_sub(2), 0, 2, h, true),
+        (w, h, 5, 5, false),
+    ];
+    for (x, y, width, height, value) in IntoIterator::into_iter(rects) {
+        tree.fill_rect(x, y, width, height, value);
+        for py in y..(y + height).min(h) {
+            for px in x..(x + width).min(w) {
+                set(&mut buf, px, py, value);
+            }
+        }
+        assert_eq!(tree.head, QuadTree::from_buf(&buf, res, use_bitmap).head);
+    }
+
+    for (x, y, value) in [
+        (w / 3, h / 3, true),
+        (w / 3, h / 3, false),
+        (0, h - 1, true),
+    ] {
+        tree.set_pixel(x, y, value);
+        set(&mut buf, x, y, value);
+    }
+    // outside of the image
+    tree.set_pixel(w, 0, true);
+    assert_eq!(tree.head, QuadTree::from_buf(&buf, res, use_bitmap).head);
+
+    for y in 0..h {
+        for x in 0..w {
+            let expected = buf.view_bits::<Msb0>()[y as usize * res.stride() * 8 + x as usize];
+            assert_eq!(tree.get_pixel(x, y), expected, "pixel {} {}", x, y);
+        }
+    }
+    assert!(!tree.get_pixel(w, h));
+
+    tree.clear(true);
+    assert_eq!(tree.head, Node::Leaf(LeafData::Feature(true)));
+}
+
+#[test]
+fn edit_pixels() {
+    for (w, h) in [(128, 64), (20, 13), (96, 96), (9, 30), (1, 1)] {
+        let res = Resolution::new(w, h).unwrap();
+        edit_and_compare(res, true);
+        edit_and_compare(res, false);
+    }
+}
+
+/// Row-major framebuffer with the pixels `lit` returns true for
+fn image(res: Resolution, lit: &dyn Fn(usize, usize) -> bool) -> Vec<u8> {
+    let mut buf = vec![0u8; res.buf_len()];
+    for y in 0..res.height() as usize {
+        for x in 0..res.width() as usize {
+            buf.view_bits_mut::<Msb0>()
+                .set(y * res.stride() * 8 + x, lit(x, y));
+        }
+    }
+    buf
+}
+
+/// Checks the set operations against the trees built from the framebuffers combined pixel by
+/// pixel, `a` with bitmaps or not and `b` the other way.
+fn combine_and_compare(res: Resolution, use_bitmap: bool) {
+    let (w, h) = (res.width() as usize, res.height() as usize);
+    // large uniform areas, noise and edges not on the squares
+    let a = image(res, &|x, y| x < w / 2 || (x * 7 + y * 3) % 5 == 0);
+    let b = image(res, &|x, y| (y > h / 3 && y < h - 2) || (x ^ y) % 3 == 0);
+    let tree_a = QuadTree::from_buf(&a, res, use_bitmap);
+    let tree_b = QuadTree::from_buf(&b, res, !use_bitmap);
+
+    let check = |tree: QuadTree, byte_op: fn(u8, u8) -> u8| {
+        let buf: Vec<_> = a.iter().zip(&b).map(|(a, b)| byte_op(*a, *b)).collect();
+        assert_eq!(tree.head, QuadTree::from_buf(&buf, res, use_bitmap).head);
+    };
+    check(tree_a.union(&tree_b), |a, b| a | b);
+    check(tree_a.intersection(&tree_b), |a, b| a & b);
+    check(tree_a.difference(&tree_b), |a, b| a & !b);
+    check(tree_a.xor(&tree_b), |a, b| a ^ b);
+
+    let inverted = image(res, &|x, y| !(x < w / 2 || (x * 7 + y * 3) % 5 == 0));
+    let expected = QuadTree::from_buf(&inverted, res, use_bitmap);
+    assert_eq!(tree_a.invert().head, expected.head);
+    assert_eq!(tree_a.invert().invert().head, tree_a.head);
+
+    // empty trees are unlit
+    let empty = QuadTree::new(res);
+    assert_eq!(tree_a.union(&empty).head, tree_a.head);
+    assert_eq!(
+        empty.invert().head,
+        QuadTree::from_buf(&image(res, &|_, _| true), res, false).head
+    );
+}
+
+#[test]
+fn set_operations() {
+    for (w, h) in [(128, 64), (20, 13), (96, 96), (9, 30), (1, 1)] {
+        let res = Resolution::new(w, h).unwrap();
+        combine_and_compare(res, true);
+        combine_and_compare(res, false);
+    }
+}
+
+#[test]
+fn to_bitmap() {
+    use super::lossy::{Lossy, PixelError};
+
+    assert_eq!(QuadTree::from_128x64(&BUF, true).to_bitmap(), BUF);
+    for (w, h) in [(128, 64), (20, 13), (96, 96), (9, 30), (1, 1)] {
+        let res = Resolution::new(w, h).unwrap();
+        let (w, h) = (w as usize, h as usize);
+        let buf = image(res, &|x, y| {
+            (x > w / 3 && y < h / 2) || (x * 5 + y) % 7 == 0 || x == w - 1
+        });
+        assert_eq!(QuadTree::new(res).to_bitmap(), vec![0; res.buf_len()]);
+
+        for use_bitmap in [true, false] {
+            let tree = QuadTree::from_buf(&buf, res, use_bitmap);
+            assert_eq!(tree.to_bitmap(), buf);
+
+            // the error of a lossy tree, checked on the tree itself
+            let mut lossy = buf.clone();
+            let error = Lossy::global(4).apply(&mut lossy, res);
+            let tree = QuadTree::from_buf(&lossy, res, use_bitmap);
+            assert_eq!(PixelError::compare(&buf, &tree.to_bitmap(), res), error);
+        }
+    }
+}
@@ -1,65 +1,160 @@
-use super::{BitVecU8, QuadTree};
+use super::{
+    lossy::{Lossy, PixelError, SetCounts},
+    rate::{RateControl, RateLimit, RateReport},
+    BitVecU8, QuadTree,
+};
+use crate::{Resolution, TreeFormat, VideoHeader};
 
+use bitvec::prelude::*;
 use std::{
     cmp::min,
-    io::{Result as IoResult, Write},
+    convert::TryFrom,
+    io::{Error, ErrorKind, Result as IoResult, Write},
+    iter,
 };
 
 pub trait Encode: Sized + Default {
-    fn encode_i_frame<W: Write>(&mut self, buf: &[u8; 1024], w: W) -> IoResult<()>;
-    fn encode_p_frame<W: Write>(&mut self, buf: &[u8; 1024], w: W) -> IoResult<()>;
+    /// Tree format of the encoded frames, recorded in the video header
+    const FORMAT: TreeFormat;
+    /// Whether the frames start with the frame header read by `dec::Motion`
+    const MOTION: bool = false;
+    fn encode_i_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, w: W) -> IoResult<()>;
+    fn encode_p_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, w: W) -> IoResult<()>;
+    /// Replaces the frame the next P-frame is encoded against, e.g. after the decoder moved it.
+    fn set_previous(&mut self, buf: &[u8], res: Resolution);
+    /// Last frame as the decoder shows it, for encoders that don't encode their input exactly
+    fn decoded(&self) -> Option<&[u8]> {
+        None
+    }
 }
 
 pub struct VideoEncoder<W, E> {
-    writer: W,
+    writer: CountingWriter<W>,
     encoder: E,
-    buf: [u8; 1024],
+    buf: Vec<u8>,
     cursor: usize,
+    resolution: Resolution,
     i_frame_interval: u16,
     frame_counter: u16,
+    frames_written: u32,
+    /// `(frame, offset)` of every I-frame, when the header asks for a seek index
+    seek_index: Option<Vec<(u32, u32)>>,
+    lossy: Lossy,
+    /// Percentage of the I-frame size above which a P-frame is replaced with it
+    scene_cut: Option<u8>,
+    rate: Option<RateControl>,
+    /// Last frame as the decoder shows it
+    shown: Option<Vec<u8>>,
+    errors: Vec<PixelError>,
+    rate_reports: Vec<RateReport>,
+}
+
+/// Keeps track of the bytes written, to find the offsets of the I-frames
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+/// How `EncoderV1` stores the pixels that changed in p-frames
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PFrames {
+    /// The leaves of the diff tree turning pixels on, then the ones turning them off,
+    /// see `QuadTree::store_as_diff`
+    #[default]
+    Split,
+    /// The lit leaves of the XOR of both frames, see `QuadTree::store_as_xor`.
+    ///
+    /// Only `dec::raw::Framebuffer` can draw these.
+    Xor,
 }
 
 #[derive(Debug, Default)]
 pub struct EncoderV1 {
     previous_tree: Option<QuadTree>,
+    previous: Option<Vec<u8>>,
+    p_frames: PFrames,
+}
+
+impl EncoderV1 {
+    pub fn new(p_frames: PFrames) -> Self {
+        Self {
+            p_frames,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_p_frames(&mut self, p_frames: PFrames) {
+        self.p_frames = p_frames;
+    }
+
+    fn encode_diff(&self, tree: &QuadTree, buf: &[u8], res: Resolution) -> IoResult<Vec<Vec<u8>>> {
+        let mut leaf_buf = Vec::with_capacity(1024);
+        match (self.p_frames, &self.previous_tree, &self.previous) {
+            (PFrames::Xor, _, Some(prev)) => {
+                let changed: Vec<u8> = buf.iter().zip(prev).map(|(a, b)| a ^ b).collect();
+                QuadTree::from_buf(&changed, res, true).store_as_xor(&mut leaf_buf)?;
+                Ok(vec![leaf_buf])
+            }
+            (_, Some(prev), _) => {
+                let (len_y, _) = tree.diff(prev).store_as_diff(&mut leaf_buf)?;
+                let no = leaf_buf.split_off(len_y);
+                Ok(vec![leaf_buf, no])
+            }
+            _ => unreachable!("p-frame without a previous frame"),
+        }
+    }
 }
 
 impl Encode for EncoderV1 {
-    fn encode_i_frame<W: Write>(&mut self, buf: &[u8; 1024], mut w: W) -> IoResult<()> {
-        let tree = QuadTree::from_128x64(buf, true);
+    const FORMAT: TreeFormat = TreeFormat::Packed;
+    fn encode_i_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, mut w: W) -> IoResult<()> {
+        let tree = QuadTree::from_buf(buf, res, true);
         let mut leaf_buf = Vec::with_capacity(1024);
         let len = tree.store_packed(&mut leaf_buf)?;
         self.previous_tree = Some(tree);
+        self.previous = Some(buf.to_vec());
 
         w.write_all(&(len as u16).to_le_bytes())?;
         w.write_all(&leaf_buf)
     }
-    fn encode_p_frame<W: Write>(&mut self, buf: &[u8; 1024], mut w: W) -> IoResult<()> {
-        let tree = QuadTree::from_128x64(buf, true);
-        Ok(if let Some(prev) = self.previous_tree.take() {
-            let diff = tree.diff(&prev);
-            let mut leaf_buf = Vec::with_capacity(1024);
-
-            let (len_y, len_n) = diff.store_as_diff(&mut leaf_buf)?;
-
-            let mut tmp = Vec::with_capacity(1024);
-            let full_len = tree.store_packed(&mut tmp)?;
+    fn encode_p_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, mut w: W) -> IoResult<()> {
+        if self.previous_tree.is_none() {
+            return self.encode_i_frame(buf, res, w);
+        }
+        let tree = QuadTree::from_buf(buf, res, true);
+        let records = self.encode_diff(&tree, buf, res)?;
 
+        let mut tmp = Vec::with_capacity(1024);
+        let full_len = tree.store_packed(&mut tmp)?;
 
-            let res = if full_len < leaf_buf.len() {
-                w.write_all(&(full_len as u16).to_le_bytes())?;
-                w.write_all(&tmp)?;
-            } else {
-                w.write_all(&(len_y as u16).to_le_bytes())?;
-                w.write_all(&leaf_buf[..len_y])?;
-                w.write_all(&(len_n as u16).to_le_bytes())?;
-                w.write_all(&leaf_buf[len_y..len_y + len_n])?;
-            };
-            self.previous_tree = Some(tree);
-            res
+        if full_len < records.iter().map(Vec::len).sum() {
+            w.write_all(&(full_len as u16).to_le_bytes())?;
+            w.write_all(&tmp)?;
         } else {
-            self.encode_p_frame(buf, w)?;
-        })
+            for record in records {
+                w.write_all(&(record.len() as u16).to_le_bytes())?;
+                w.write_all(&record)?;
+            }
+        };
+        self.previous_tree = Some(tree);
+        self.previous = Some(buf.to_vec());
+        Ok(())
+    }
+    fn set_previous(&mut self, buf: &[u8], res: Resolution) {
+        self.previous_tree = Some(QuadTree::from_buf(buf, res, true));
+        self.previous = Some(buf.to_vec());
     }
 }
 
@@ -76,54 +171,885 @@ fn write_bits(bits: &BitVecU8, mut w: impl Write) -> IoResult<()> {
 }
 
 impl Encode for EncoderV2 {
-    fn encode_i_frame<W: Write>(&mut self, buf: &[u8; 1024], w: W) -> IoResult<()> {
-        let tree = QuadTree::from_128x64(buf, false);
+    const FORMAT: TreeFormat = TreeFormat::Compact;
+    fn encode_i_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, w: W) -> IoResult<()> {
+        let tree = QuadTree::from_buf(buf, res, false);
         write_bits(&tree.collect_compact().unwrap(), w)?;
         self.previous_tree = Some(tree);
         Ok(())
     }
-    fn encode_p_frame<W: Write>(&mut self, buf: &[u8; 1024], w: W) -> IoResult<()> {
+    fn encode_p_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, w: W) -> IoResult<()> {
         if let Some(prev) = self.previous_tree.take() {
-            let tree = QuadTree::from_128x64(buf, false);
+            let tree = QuadTree::from_buf(buf, res, false);
             let diff = tree.diff(&prev);
             self.previous_tree = Some(tree);
             write_bits(&diff.collect_compact().unwrap(), w)
         } else {
-            eprintln!("iframe fallback");
-            self.encode_i_frame(buf, w)
+            self.encode_i_frame(buf, res, w)
+        }
+    }
+    fn set_previous(&mut self, buf: &[u8], res: Resolution) {
+        self.previous_tree = Some(QuadTree::from_buf(buf, res, false));
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EncoderV3 {
+    previous_tree: Option<QuadTree>,
+}
+
+impl Encode for EncoderV3 {
+    const FORMAT: TreeFormat = TreeFormat::CompactBitmap;
+    fn encode_i_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, w: W) -> IoResult<()> {
+        let tree = QuadTree::from_buf(buf, res, false);
+        write_bits(&tree.collect_compact_bitmap(&tree), w)?;
+        self.previous_tree = Some(tree);
+        Ok(())
+    }
+    fn encode_p_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, w: W) -> IoResult<()> {
+        if let Some(prev) = self.previous_tree.take() {
+            let tree = QuadTree::from_buf(buf, res, false);
+            let diff = tree.diff(&prev);
+            write_bits(&diff.collect_compact_bitmap(&tree), w)?;
+            self.previous_tree = Some(tree);
+            Ok(())
+        } else {
+            self.encode_i_frame(buf, res, w)
+        }
+    }
+    fn set_previous(&mut self, buf: &[u8], res: Resolution) {
+        self.previous_tree = Some(QuadTree::from_buf(buf, res, false));
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EncoderV4 {
+    previous_tree: Option<QuadTree>,
+}
+
+fn write_bytes(bytes: &[u8], mut w: impl Write) -> IoResult<()> {
+    w.write_all(&(bytes.len() as u16).to_le_bytes())?;
+    w.write_all(bytes)
+}
+
+impl Encode for EncoderV4 {
+    const FORMAT: TreeFormat = TreeFormat::CompactEntropy;
+    fn encode_i_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, w: W) -> IoResult<()> {
+        let tree = QuadTree::from_buf(buf, res, false);
+        write_bytes(&tree.collect_compact_entropy().unwrap(), w)?;
+        self.previous_tree = Some(tree);
+        Ok(())
+    }
+    fn encode_p_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, w: W) -> IoResult<()> {
+        if let Some(prev) = self.previous_tree.take() {
+            let tree = QuadTree::from_buf(buf, res, false);
+            let diff = tree.diff(&prev);
+            self.previous_tree = Some(tree);
+            write_bytes(&diff.collect_compact_entropy().unwrap(), w)
+        } else {
+            self.encode_i_frame(buf, res, w)
+        }
+    }
+    fn set_previous(&mut self, buf: &[u8], res: Resolution) {
+        self.previous_tree = Some(QuadTree::from_buf(buf, res, false));
+    }
+}
+
+/// Leaves out the changes of P-frames that touch only a few pixels of a sector.
+///
+/// The frames are compared to what the decoder shows rather than to the previous input, so
+/// the skipped changes add up until their sector goes past the allowance and gets updated,
+/// or until the next I-frame.
+#[derive(Debug, Default)]
+pub struct TemporalLossy<E> {
+    inner: E,
+    lossy: Lossy,
+    /// Last frame as the decoder shows it
+    shown: Option<Vec<u8>>,
+}
+
+impl<E: Encode> TemporalLossy<E> {
+    pub fn new(inner: E, lossy: Lossy) -> Self {
+        Self {
+            inner,
+            lossy,
+            shown: None,
+        }
+    }
+
+    /// Changed pixels a sector may leave out in the next P-frames, lossless by default.
+    pub fn set_lossy(&mut self, lossy: Lossy) {
+        self.lossy = lossy;
+    }
+
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+}
+
+impl<E: Encode> Encode for TemporalLossy<E> {
+    const FORMAT: TreeFormat = E::FORMAT;
+    const MOTION: bool = E::MOTION;
+    fn encode_i_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, w: W) -> IoResult<()> {
+        self.shown = Some(buf.to_vec());
+        self.inner.encode_i_frame(buf, res, w)
+    }
+    fn encode_p_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, w: W) -> IoResult<()> {
+        match &mut self.shown {
+            Some(shown) => {
+                self.lossy.apply_changes(shown, buf, res);
+                self.inner.encode_p_frame(shown, res, w)
+            }
+            None => self.encode_i_frame(buf, res, w),
+        }
+    }
+    fn set_previous(&mut self, buf: &[u8], res: Resolution) {
+        self.shown = Some(buf.to_vec());
+        self.inner.set_previous(buf, res);
+    }
+    fn decoded(&self) -> Option<&[u8]> {
+        self.shown.as_deref()
+    }
+}
+
+/// Encodes scrolling and panning as a shift of the previous frame, and moving sprites as copies
+/// of its squares, followed by the P-frame of what still differs, see `dec::Motion`.
+///
+/// Every P-frame looks for the shift up to `range` pixels in each direction leaving the fewest
+/// differing pixels, then for changed squares of 32 down to 8 pixels found exactly within
+/// `range` of the frame, shifted or not.
+/// The combinations are all encoded and the smallest frame is kept, so the P-frames are never
+/// larger than without moves, besides the byte of the frame header.
+/// The searches compare pixels for every offset, their cost grows with the square of the range.
+#[derive(Debug)]
+pub struct MotionEncoder<E> {
+    inner: E,
+    range: u16,
+    /// Last frame as the decoder shows it
+    previous: Option<Vec<u8>>,
+}
+
+impl<E: Encode> Default for MotionEncoder<E> {
+    fn default() -> Self {
+        Self::new(E::default(), 8)
+    }
+}
+
+impl<E: Encode> MotionEncoder<E> {
+    pub fn new(inner: E, range: u16) -> Self {
+        Self {
+            inner,
+            range,
+            previous: None,
+        }
+    }
+
+    /// Largest shift tried in each direction, 8 pixels by default.
+    pub fn set_range(&mut self, range: u16) {
+        self.range = range;
+    }
+
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    /// Shift with the fewest pixels differing from `buf`, `None` if not moving is as good.
+    fn find_shift(&self, previous: &[u8], buf: &[u8], res: Resolution) -> Option<(i16, i16)> {
+        let range = self.range.min(i16::MAX as u16) as i16;
+        let max_x = range.min(res.width() as i16 - 1);
+        let max_y = range.min(res.height() as i16 - 1);
+
+        let mut best = None;
+        let mut fewest = PixelError::compare(previous, buf, res).wrong;
+        for dy in -max_y..=max_y {
+            for dx in -max_x..=max_x {
+                if fewest == 0 {
+                    return best;
+                }
+                if (dx, dy) == (0, 0) {
+                    continue;
+                }
+                let wrong =
+                    PixelError::compare(&shift_frame(previous, res, dx, dy), buf, res).wrong;
+                if wrong < fewest {
+                    best = Some((dx, dy));
+                    fewest = wrong;
+                }
+            }
+        }
+        best
+    }
+
+    /// Finds the changed squares of `working` that are found elsewhere in it, and copies them
+    /// as the decoder will.
+    ///
+    /// `counts` are taken before the copies, which only change the squares already visited.
+    fn find_copies(
+        &self,
+        working: &mut [u8],
+        buf: &[u8],
+        counts: &FrameCounts,
+        square: Square,
+        copies: &mut Copies,
+    ) {
+        let Square { x, y, side, res } = square;
+        // children can't cost more than their parent
+        if counts.diff_nodes(square) <= COPY_NODES {
+            return;
+        }
+        if side <= MAX_BLOCK {
+            if let Some((dx, dy)) = self.find_block(working, buf, counts, square) {
+                // the copy reads its source before writing, so the square ends up as in `buf`
+                for (x, y) in square.pixels() {
+                    set_pixel(working, res, x, y, pixel(buf, res, x, y));
+                }
+                copies.push(x, y, side, dx, dy);
+                return;
+            }
+        }
+        if side > MIN_BLOCK {
+            for child in square.children() {
+                self.find_copies(working, buf, counts, child, copies);
+            }
         }
     }
+
+    /// Closest offset of `working` matching the square of `buf` exactly.
+    fn find_block(
+        &self,
+        working: &[u8],
+        buf: &[u8],
+        counts: &FrameCounts,
+        square: Square,
+    ) -> Option<(i16, i16)> {
+        let range = self.range.min(i16::MAX as u16) as i32;
+        let res = square.res;
+        let (w, h) = counts.lit.clip(square.x, square.y, square.side);
+        let lit = counts.lit.count(square.x, square.y, w, h);
+        // squares with other counts of lit pixels can't match, going by the frame before the
+        // copies is a good enough guess to skip comparing most of them
+        let mut offsets: Vec<_> = (-range..=range)
+            .flat_map(|dy| (-range..=range).map(move |dx| (dx, dy)))
+            .filter(|o| *o != (0, 0))
+            .filter(|(dx, dy)| {
+                let (x, y) = (square.x as i32 - dx, square.y as i32 - dy);
+                counts.start_lit(x, y, w, h) == lit
+            })
+            .collect();
+        offsets.sort_by_key(|(dx, dy)| dx.abs() + dy.abs());
+        offsets
+            .into_iter()
+            .find(|&(dx, dy)| {
+                square.pixels().all(|(x, y)| {
+                    let (sx, sy) = (x as i32 - dx, y as i32 - dy);
+                    let source = sx >= 0 && sy >= 0 && pixel(working, res, sx as u32, sy as u32);
+                    source == pixel(buf, res, x, y)
+                })
+            })
+            .map(|(dx, dy)| (dx as i16, dy as i16))
+    }
+
+    /// Encodes a P-frame against `start` and returns the records with the frame as the
+    /// decoder shows it.
+    fn encode_from(
+        &mut self,
+        start: &[u8],
+        buf: &[u8],
+        res: Resolution,
+    ) -> IoResult<(Vec<u8>, Vec<u8>)> {
+        self.inner.set_previous(start, res);
+        let mut records = Vec::with_capacity(1024);
+        self.inner.encode_p_frame(buf, res, &mut records)?;
+        let decoded = self.inner.decoded().unwrap_or(buf).to_vec();
+        Ok((records, decoded))
+    }
+}
+
+impl<E: Encode> Encode for MotionEncoder<E> {
+    const FORMAT: TreeFormat = E::FORMAT;
+    const MOTION: bool = true;
+    fn encode_i_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, w: W) -> IoResult<()> {
+        let mut records = Vec::with_capacity(1024);
+        self.inner.encode_i_frame(buf, res, &mut records)?;
+        self.previous = Some(self.inner.decoded().unwrap_or(buf).to_vec());
+        write_records(&records, frame_header(None, &Copies::default()), w)
+    }
+    fn encode_p_frame<W: Write>(&mut self, buf: &[u8], res: Resolution, w: W) -> IoResult<()> {
+        let previous = match self.previous.take() {
+            Some(p) => p,
+            None => return self.encode_i_frame(buf, res, w),
+        };
+        let root = Square {
+            x: 0,
+            y: 0,
+            side: res.side(),
+            res,
+        };
+        // the moves cost bytes of their own and shrink the tree by an amount that depends on
+        // the format, so every combination is encoded and the smallest kept
+        let mut starts = vec![(None, Copies::default(), previous.clone())];
+        if let Some((dx, dy)) = self.find_shift(&previous, buf, res) {
+            let shifted = shift_frame(&previous, res, dx, dy);
+            starts.push((Some((dx, dy)), Copies::default(), shifted));
+        }
+        for i in 0..starts.len() {
+            let (shift, _, start) = &starts[i];
+            let (shift, mut copied, mut copies) = (*shift, start.clone(), Copies::default());
+            let counts = FrameCounts::new(start, buf, res);
+            self.find_copies(&mut copied, buf, &counts, root, &mut copies);
+            if copies.count > 0 {
+                starts.push((shift, copies, copied));
+            }
+        }
+
+        let mut encoded = Vec::with_capacity(starts.len());
+        for (shift, copies, start) in &starts {
+            let (records, decoded) = self.encode_from(start, buf, res)?;
+            encoded.push((frame_header(*shift, copies), records, decoded));
+        }
+        let (header, records, decoded) = encoded
+            .into_iter()
+            .min_by_key(|(header, records, _)| header.len() + records.len())
+            .unwrap();
+        // the encoder has to continue from the kept frame
+        self.inner.set_previous(&decoded, res);
+        self.previous = Some(decoded);
+        write_records(&records, header, w)
+    }
+    fn set_previous(&mut self, buf: &[u8], res: Resolution) {
+        self.previous = Some(buf.to_vec());
+        self.inner.set_previous(buf, res);
+    }
+    fn decoded(&self) -> Option<&[u8]> {
+        self.inner.decoded()
+    }
+}
+
+/// Frame header moving the previous frame by `shift` and copying `copies`, see `dec::Motion`
+fn frame_header(shift: Option<(i16, i16)>, copies: &Copies) -> Vec<u8> {
+    let mut header = vec![0];
+    if let Some((dx, dy)) = shift {
+        header[0] |= 1;
+        header.extend_from_slice(&dx.to_le_bytes());
+        header.extend_from_slice(&dy.to_le_bytes());
+    }
+    if copies.count > 0 {
+        header[0] |= 2;
+        header.extend_from_slice(&copies.count.to_le_bytes());
+        header.extend_from_slice(&copies.bytes);
+    }
+    header
+}
+
+fn check_resolution<E: Encode>(resolution: Resolution) -> IoResult<()> {
+    if E::FORMAT == TreeFormat::Packed && resolution.depth() > 7 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "The packed format only fits images up to 128 pixels per side",
+        ));
+    }
+    Ok(())
+}
+
+/// Writes the records of a frame, adding the frame header to the first one and plain headers
+/// to the others.
+fn write_records(records: &[u8], mut header: Vec<u8>, mut w: impl Write) -> IoResult<()> {
+    let mut rest = records;
+    while rest.len() >= 2 {
+        let len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+        let (record, next) = rest[2..].split_at(len);
+        let full_len = u16::try_from(header.len() + len)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "The frame doesn't fit in a record"))?;
+        w.write_all(&full_len.to_le_bytes())?;
+        w.write_all(&header)?;
+        w.write_all(record)?;
+        header = vec![0];
+        rest = next;
+    }
+    Ok(())
+}
+
+/// Smallest and largest squares `MotionEncoder` copies
+const MIN_BLOCK: u32 = 8;
+const MAX_BLOCK: u32 = 32;
+
+/// Nodes of a compact P-frame worth a block copy, each costing 2 bits
+const COPY_NODES: u32 = 36;
+
+/// Changed and lit pixels of the squares of a P-frame, before the copies
+struct FrameCounts {
+    changed: SetCounts,
+    lit: SetCounts,
+    /// Lit pixels of the frame the P-frame starts from
+    start: SetCounts,
+}
+
+impl FrameCounts {
+    fn new(start: &[u8], buf: &[u8], res: Resolution) -> Self {
+        let changes: Vec<u8> = start.iter().zip(buf).map(|(p, b)| p ^ b).collect();
+        Self {
+            changed: SetCounts::new(&changes, res),
+            lit: SetCounts::new(buf, res),
+            start: SetCounts::new(start, res),
+        }
+    }
+
+    /// Lit pixels of a rectangle of the start frame, which can reach out of the image
+    fn start_lit(&self, x: i32, y: i32, w: u32, h: u32) -> u32 {
+        let (x_end, y_end) = (x + w as i32, y + h as i32);
+        let (x, y) = (x.max(0) as u32, y.max(0) as u32);
+        if x_end <= x as i32 || y_end <= y as i32 {
+            return 0;
+        }
+        let (w, h) = self
+            .start
+            .clip(x, y, (x_end as u32 - x).max(y_end as u32 - y));
+        let (w, h) = (w.min(x_end as u32 - x), h.min(y_end as u32 - y));
+        self.start.count(x, y, w, h)
+    }
+
+    /// Nodes of the compact P-frame tree of a square
+    fn diff_nodes(&self, square: Square) -> u32 {
+        let (w, h) = self.lit.clip(square.x, square.y, square.side);
+        if w == 0 || h == 0 {
+            // outside of the image
+            return 0;
+        }
+        let changed = self.changed.count(square.x, square.y, w, h);
+        let lit = self.lit.count(square.x, square.y, w, h);
+        if changed == 0 || lit == 0 || lit == w * h || square.side == 1 {
+            // empty node or leaf
+            return 1;
+        }
+        1 + square
+            .children()
+            .map(|child| self.diff_nodes(child))
+            .sum::<u32>()
+    }
+}
+
+/// Block copies of a frame header, as written
+#[derive(Default)]
+struct Copies {
+    count: u16,
+    bytes: Vec<u8>,
+}
+
+impl Copies {
+    fn push(&mut self, x: u32, y: u32, side: u32, dx: i16, dy: i16) {
+        self.count += 1;
+        self.bytes.extend_from_slice(&(x as u16).to_le_bytes());
+        self.bytes.extend_from_slice(&(y as u16).to_le_bytes());
+        self.bytes.push(side.trailing_zeros() as u8);
+        self.bytes.extend_from_slice(&dx.to_le_bytes());
+        self.bytes.extend_from_slice(&dy.to_le_bytes());
+    }
+}
+
+/// Square of the quadtree of a frame
+#[derive(Clone, Copy)]
+struct Square {
+    x: u32,
+    y: u32,
+    side: u32,
+    res: Resolution,
+}
+
+impl Square {
+    /// Pixels of the square inside the image
+    fn pixels(self) -> impl Iterator<Item = (u32, u32)> {
+        let x_end = (self.res.width() as u32).min(self.x + self.side);
+        let y_end = (self.res.height() as u32).min(self.y + self.side);
+        (self.y..y_end).flat_map(move |y| (self.x..x_end).map(move |x| (x, y)))
+    }
+
+    fn children(self) -> impl Iterator<Item = Square> {
+        let half = self.side / 2;
+        // arrays iterate by reference in edition 2018
+        IntoIterator::into_iter([(0, 0), (half, 0), (0, half), (half, half)]).map(
+            move |(dx, dy)| Square {
+                x: self.x + dx,
+                y: self.y + dy,
+                side: half,
+                ..self
+            },
+        )
+    }
+}
+
+/// Allowances of `Lossy::global` tried in turn to fit a frame into its rate limit, doubling up
+/// to the pixels of the frame, which lose all of their details or changes.
+fn rate_levels(res: Resolution) -> impl Iterator<Item = u32> {
+    let pixels = res.width() as u32 * res.height() as u32;
+    iter::once(0).chain(iter::successors(Some(1), move |l| {
+        (*l < pixels).then(|| (l * 2).min(pixels))
+    }))
+}
+
+/// Frame encoded by `VideoEncoder` to see if it fits its rate limit
+struct Trial {
+    bytes: Vec<u8>,
+    /// The frame as the decoder shows it
+    shown: Vec<u8>,
+    level: u32,
+    i_frame: bool,
+}
+
+/// Pixel of a row-major buffer, unlit outside of the image
+fn pixel(buf: &[u8], res: Resolution, x: u32, y: u32) -> bool {
+    x < res.width() as u32
+        && y < res.height() as u32
+        && buf[y as usize * res.stride() + x as usize / 8] & 0x80 >> (x % 8) != 0
+}
+
+fn set_pixel(buf: &mut [u8], res: Resolution, x: u32, y: u32, value: bool) {
+    let (index, mask) = (y as usize * res.stride() + x as usize / 8, 0x80 >> (x % 8));
+    if value {
+        buf[index] |= mask;
+    } else {
+        buf[index] &= !mask;
+    }
+}
+
+/// Moves a row-major buffer right and down by `dx` and `dy` pixels, leaving the uncovered
+/// pixels unlit.
+fn shift_frame(buf: &[u8], res: Resolution, dx: i16, dy: i16) -> Vec<u8> {
+    let (width, height) = (res.width() as i32, res.height() as i32);
+    let (dx, dy) = (dx as i32, dy as i32);
+    let stride = res.stride();
+    let mut out = vec![0; buf.len()];
+
+    let len = (width - dx.abs()).max(0) as usize;
+    let (from, to) = if dx > 0 {
+        (0, dx as usize)
+    } else {
+        (-dx as usize, 0)
+    };
+    for y in 0..height {
+        let src_y = y - dy;
+        if len == 0 || !(0..height).contains(&src_y) {
+            continue;
+        }
+        let src = buf[src_y as usize * stride..][..stride].view_bits::<Msb0>();
+        let dst = out[y as usize * stride..][..stride].view_bits_mut::<Msb0>();
+        dst[to..to + len].copy_from_bitslice(&src[from..from + len]);
+    }
+    out
 }
 
 impl<W: Write, E: Encode> VideoEncoder<W, E> {
+    /// Writes a bare stream of 128x64 frames, without a header.
     pub fn new(writer: W, i_frame_interval: u16) -> Self {
+        Self::headerless(writer, Resolution::default(), i_frame_interval)
+    }
+
+    /// Writes a bare stream of frames of any resolution, without a header.
+    ///
+    /// The decoder has to be given the same resolution.
+    pub fn with_resolution(
+        writer: W,
+        resolution: Resolution,
+        i_frame_interval: u16,
+    ) -> IoResult<Self> {
+        check_resolution::<E>(resolution)?;
+        Ok(Self::headerless(writer, resolution, i_frame_interval))
+    }
+
+    /// Writes a container header before the frames.
+    ///
+    /// The format recorded in the header is replaced with the one of the encoder.
+    /// If the header is `indexed`, `finish` has to be called to write the seek index.
+    pub fn with_header(mut writer: W, mut header: VideoHeader) -> IoResult<Self> {
+        check_resolution::<E>(header.resolution)?;
+        header.format = E::FORMAT;
+        header.motion = E::MOTION;
+        writer.write_all(&header.to_bytes())?;
+
+        let mut enc = Self::headerless(writer, header.resolution, header.i_frame_interval);
+        if header.indexed {
+            enc.seek_index = Some(Vec::new());
+        }
+        Ok(enc)
+    }
+
+    fn headerless(writer: W, resolution: Resolution, i_frame_interval: u16) -> Self {
         Self {
-            writer,
+            writer: CountingWriter {
+                inner: writer,
+                count: 0,
+            },
             encoder: Default::default(),
-            buf: [0; 1024],
+            buf: vec![0; resolution.buf_len()],
             cursor: 0,
+            resolution,
             i_frame_interval,
             frame_counter: i_frame_interval,
+            frames_written: 0,
+            seek_index: None,
+            lossy: Default::default(),
+            scene_cut: None,
+            rate: None,
+            shown: None,
+            errors: Vec::new(),
+            rate_reports: Vec::new(),
+        }
+    }
+
+    /// The encoder of the frames, to change its settings between frames
+    pub fn encoder_mut(&mut self) -> &mut E {
+        &mut self.encoder
+    }
+
+    /// Lets the next frames lose pixels to shrink their trees, see `Lossy`.
+    pub fn set_lossy(&mut self, lossy: Lossy) {
+        self.lossy = lossy;
+    }
+
+    /// Starts a new I-frame at scene cuts, when a P-frame would take more than `percent` of
+    /// the size of the frame encoded on its own.
+    ///
+    /// The I-frame interval restarts at every cut, so it becomes the longest run of P-frames.
+    /// Each P-frame is encoded both ways to compare them.
+    pub fn set_scene_cut(&mut self, percent: Option<u8>) {
+        self.scene_cut = percent;
+    }
+
+    /// Keeps the next frames under a size limit, losing pixels or postponing I-frames when
+    /// they don't fit.
+    ///
+    /// Frames over the budget are encoded again with growing allowances of `Lossy::global`:
+    /// I-frames lose the minority pixels of their squares, P-frames leave out the changes of
+    /// theirs like `TemporalLossy`.
+    /// A due I-frame that doesn't fit is sent as a P-frame, and tried again on the next frame.
+    /// Frames that don't fit at the largest allowance are written as small as they got,
+    /// see `rate_reports`.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.rate = limit.map(RateControl::new);
+    }
+
+    /// Pixels lost in each frame encoded so far
+    pub fn errors(&self) -> &[PixelError] {
+        &self.errors
+    }
+
+    /// How each frame encoded under a rate limit was fit into its budget
+    pub fn rate_reports(&self) -> &[RateReport] {
+        &self.rate_reports
+    }
+
+    /// Encodes the last partial frame, if any, writes the seek index and returns the inner
+    /// writer.
+    pub fn finish(mut self) -> IoResult<W> {
+        self.flush()?;
+
+        if let Some(index) = self.seek_index.take() {
+            for (frame, offset) in &index {
+                self.writer.write_all(&frame.to_le_bytes())?;
+                self.writer.write_all(&offset.to_le_bytes())?;
+            }
+            self.writer.write_all(&(index.len() as u32).to_le_bytes())?;
+            self.writer.write_all(&VideoHeader::INDEX_MAGIC)?;
+            self.writer.flush()?;
         }
+
+        Ok(self.writer.inner)
     }
 
     fn encode_buf(&mut self) -> IoResult<()> {
         self.cursor = 0;
-        if self.frame_counter < self.i_frame_interval {
-            self.frame_counter += 1;
-            self.encoder.encode_p_frame(&self.buf, &mut self.writer)
+        self.frames_written += 1;
+        let mut frame = self.buf.clone();
+        self.lossy.apply(&mut frame, self.resolution);
+
+        let shown = if self.rate.is_some() {
+            self.encode_within_budget(&frame)?
         } else {
-            self.frame_counter = 1;
-            self.encoder.encode_i_frame(&self.buf, &mut self.writer)
+            if self.frame_counter >= self.i_frame_interval {
+                self.start_i_frame()?;
+                self.encoder
+                    .encode_i_frame(&frame, self.resolution, &mut self.writer)?;
+            } else if let Some(percent) = self.scene_cut {
+                self.encode_or_cut(&frame, percent)?;
+            } else {
+                self.frame_counter += 1;
+                self.encoder
+                    .encode_p_frame(&frame, self.resolution, &mut self.writer)?;
+            }
+            self.encoder.decoded().unwrap_or(&frame).to_vec()
+        };
+
+        self.errors
+            .push(PixelError::compare(&self.buf, &shown, self.resolution));
+        self.shown = Some(shown);
+        Ok(())
+    }
+
+    /// Writes the least degraded encoding of `frame` that fits the budget of the rate limit
+    /// and returns the frame the decoder shows after it.
+    fn encode_within_budget(&mut self, frame: &[u8]) -> IoResult<Vec<u8>> {
+        let mut rate = self.rate.take().unwrap();
+        let budget = rate.budget();
+        let i_frame_due = self.frame_counter >= self.i_frame_interval || self.shown.is_none();
+
+        // binary search for the smallest allowance that fits, after trying the frame as it is
+        let levels: Vec<u32> = rate_levels(self.resolution).collect();
+        let (mut fits, mut too_big) = (None, None);
+        let (mut low, mut high) = (0, levels.len());
+        while low < high {
+            let mid = if low == 0 { 0 } else { (low + high) / 2 };
+            match self.fit_level(frame, levels[mid], budget, i_frame_due)? {
+                Ok(trial) => {
+                    fits = Some(trial);
+                    high = mid;
+                }
+                Err(trial) => {
+                    too_big = IntoIterator::into_iter([too_big, Some(trial)])
+                        .flatten()
+                        .min_by_key(|t: &Trial| t.bytes.len());
+                    low = mid + 1;
+                }
+            }
+        }
+        let trial = fits.or(too_big).unwrap();
+
+        // the trials left the encoder at the last one
+        self.encoder.set_previous(&trial.shown, self.resolution);
+        if trial.i_frame {
+            self.start_i_frame()?;
+        } else {
+            self.frame_counter = self.frame_counter.saturating_add(1);
+        }
+        self.writer.write_all(&trial.bytes)?;
+        rate.spend(trial.bytes.len());
+        self.rate = Some(rate);
+        self.rate_reports.push(RateReport {
+            budget,
+            size: trial.bytes.len(),
+            level: trial.level,
+            postponed_i_frame: i_frame_due && !trial.i_frame,
+        });
+        Ok(trial.shown)
+    }
+
+    /// Tries the kinds of frames allowed for `frame` with the allowance `level`, returning the
+    /// first one that fits the budget or else the smallest.
+    fn fit_level(
+        &mut self,
+        frame: &[u8],
+        level: u32,
+        budget: usize,
+        i_frame_due: bool,
+    ) -> IoResult<Result<Trial, Trial>> {
+        // in order of preference
+        let mut trials = Vec::with_capacity(2);
+        if i_frame_due {
+            trials.push(self.trial(frame, level, true)?);
+        } else {
+            trials.push(self.trial(frame, level, false)?);
+            if let Some(percent) = self.scene_cut {
+                let i_frame = self.trial(frame, level, true)?;
+                if trials[0].bytes.len() * 100 > i_frame.bytes.len() * percent as usize {
+                    trials.insert(0, i_frame);
+                } else {
+                    trials.push(i_frame);
+                }
+            }
+        }
+        if i_frame_due && trials[0].bytes.len() > budget && self.shown.is_some() {
+            trials.push(self.trial(frame, level, false)?);
+        }
+
+        match trials.iter().position(|t| t.bytes.len() <= budget) {
+            Some(i) => Ok(Ok(trials.swap_remove(i))),
+            None => Ok(Err(trials
+                .into_iter()
+                .min_by_key(|t| t.bytes.len())
+                .unwrap())),
+        }
+    }
+
+    /// Encodes `frame` on the side, losing pixels within `Lossy::global(level)`.
+    ///
+    /// P-frames are encoded against the last frame written, not the last trial.
+    fn trial(&mut self, frame: &[u8], level: u32, i_frame: bool) -> IoResult<Trial> {
+        let lossy = Lossy::global(level);
+        let res = self.resolution;
+        let mut bytes = Vec::new();
+        let shown = if i_frame {
+            let mut frame = frame.to_vec();
+            lossy.apply(&mut frame, res);
+            self.encoder.encode_i_frame(&frame, res, &mut bytes)?;
+            frame
+        } else {
+            let previous = self.shown.as_deref().unwrap();
+            let mut shown = previous.to_vec();
+            if lossy.is_lossless() {
+                shown.copy_from_slice(frame);
+            } else {
+                lossy.apply_changes(&mut shown, frame, res);
+            }
+            self.encoder.set_previous(previous, res);
+            self.encoder.encode_p_frame(&shown, res, &mut bytes)?;
+            shown
+        };
+        Ok(Trial {
+            shown: self.encoder.decoded().map_or(shown, <[u8]>::to_vec),
+            bytes,
+            level,
+            i_frame,
+        })
+    }
+
+    /// Records the I-frame about to be written in the seek index and restarts the interval.
+    ///
+    /// Fails once an indexed stream passes 4 GiB, the offsets of the index are `u32`.
+    fn start_i_frame(&mut self) -> IoResult<()> {
+        if let Some(index) = &mut self.seek_index {
+            let offset = u32::try_from(self.writer.count).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "The seek index only fits offsets up to 4 GiB",
+                )
+            })?;
+            index.push((self.frames_written - 1, offset));
+        }
+        self.frame_counter = 1;
+        Ok(())
+    }
+
+    /// Writes the P-frame of `frame`, or an I-frame if the P-frame is over `percent` of its
+    /// size.
+    fn encode_or_cut(&mut self, frame: &[u8], percent: u8) -> IoResult<()> {
+        let mut p_frame = Vec::new();
+        self.encoder
+            .encode_p_frame(frame, self.resolution, &mut p_frame)?;
+        let shown = self.encoder.decoded().unwrap_or(frame).to_vec();
+
+        let mut i_frame = Vec::new();
+        self.encoder
+            .encode_i_frame(frame, self.resolution, &mut i_frame)?;
+
+        if p_frame.len() * 100 > i_frame.len() * percent as usize {
+            self.start_i_frame()?;
+            self.writer.write_all(&i_frame)
+        } else {
+            // back to the frame the decoder shows after the P-frame
+            self.encoder.set_previous(&shown, self.resolution);
+            self.frame_counter += 1;
+            self.writer.write_all(&p_frame)
         }
     }
 }
 
 impl<W: Write, E: Encode> Write for VideoEncoder<W, E> {
+    /// Pads and encodes a partially written frame, then flushes the inner writer.
     fn flush(&mut self) -> IoResult<()> {
-        self.buf[self.cursor..].fill(0);
+        if self.cursor > 0 {
+            self.buf[self.cursor..].fill(0);
+            self.encode_buf()?;
+        }
 
-        self.encode_buf()
+        self.writer.flush()
     }
 
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
@@ -131,7 +1057,9 @@ impl<W: Write, E: Encode> Write for VideoEncoder<W, E> {
         self.buf[self.cursor..self.cursor + to_write].copy_from_slice(&buf[..to_write]);
         self.cursor += to_write;
 
-        self.encode_buf()?;
+        if self.cursor == self.buf.len() {
+            self.encode_buf()?;
+        }
 
         Ok(to_write)
     }
@@ -0,0 +1,221 @@
+//! Adaptive binary range coder for the node codes of compact trees.
+//!
+//! Every two-bit node code is coded as two binary decisions, leaf or not and then the second bit,
+//! with probabilities picked by the depth of the node and the code of its previous sibling.
+//! The model is reset at the start of every frame and only takes a few hundred bytes,
+//! so decoding works without allocations.
+
+use crate::{MAX_DEPTH, Position};
+
+#[cfg(feature = "dec")]
+use crate::dec::ParseError;
+
+/// Precision of the probabilities, in bits
+const PROB_BITS: u32 = 11;
+/// How fast the probabilities adapt, higher is slower
+const MOVE_BITS: u32 = 5;
+const TOP: u32 = 1 << 24;
+
+/// Code used as the context of the first child of a branch
+const FIRST_CHILD: usize = 4;
+
+/// Probabilities of the node codes, by depth and code of the previous sibling.
+///
+/// The parent isn't part of the context: every parent is a branch, so its code says nothing.
+/// Adding the parent's previous sibling instead splits every context five ways,
+/// which made the synthetic test videos about 2% larger:
+/// with the model reset every frame, the extra contexts don't see enough nodes to adapt.
+#[derive(Debug, Clone)]
+pub(crate) struct NodeModel {
+    probs: [[[u16; 3]; 5]; MAX_DEPTH + 1],
+    /// Code of the last node seen at each depth
+    previous: [u8; MAX_DEPTH + 1],
+}
+
+impl Default for NodeModel {
+    fn default() -> Self {
+        Self {
+            probs: [[[1 << (PROB_BITS - 1); 3]; 5]; MAX_DEPTH + 1],
+            previous: [0; MAX_DEPTH + 1],
+        }
+    }
+}
+
+impl NodeModel {
+    /// Probabilities of the node at `pos`, `[leaf, second bit of non-leaves, second bit of leaves]`
+    fn context(&mut self, pos: &Position) -> &mut [u16; 3] {
+        let depth = pos.len();
+        let sibling = match pos.last() {
+            Some(0) | None => FIRST_CHILD,
+            Some(_) => self.previous[depth] as usize,
+        };
+        &mut self.probs[depth][sibling]
+    }
+
+    #[cfg(feature = "enc")]
+    pub(crate) fn encode(&mut self, rc: &mut RangeEncoder, pos: &Position, code: u8) {
+        let probs = self.context(pos);
+        let leaf = code >> 1;
+        rc.encode_bit(&mut probs[0], leaf == 1);
+        rc.encode_bit(&mut probs[1 + leaf as usize], code & 1 == 1);
+        self.previous[pos.len()] = code;
+    }
+
+    #[cfg(feature = "dec")]
+    pub(crate) fn decode(
+        &mut self,
+        rc: &mut RangeDecoder,
+        pos: &Position,
+    ) -> Result<u8, ParseError> {
+        let probs = self.context(pos);
+        let leaf = rc.decode_bit(&mut probs[0])? as u8;
+        let code = leaf << 1 | rc.decode_bit(&mut probs[1 + leaf as usize])? as u8;
+        self.previous[pos.len()] = code;
+        Ok(code)
+    }
+}
+
+#[cfg(feature = "enc")]
+pub(crate) struct RangeEncoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u32,
+    out: std::vec::Vec<u8>,
+}
+
+#[cfg(feature = "enc")]
+impl Default for RangeEncoder {
+    fn default() -> Self {
+        Self {
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            cache_size: 1,
+            out: Default::default(),
+        }
+    }
+}
+
+#[cfg(feature = "enc")]
+impl RangeEncoder {
+    pub(crate) fn encode_bit(&mut self, prob: &mut u16, bit: bool) {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        if bit {
+            self.low += bound as u64;
+            self.range -= bound;
+            *prob -= *prob >> MOVE_BITS;
+        } else {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+        }
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xFF00_0000 || self.low > u32::MAX as u64 {
+            // resolve the pending bytes, now that the carry is known
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            while self.cache_size > 0 {
+                self.out.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+                self.cache_size -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+    }
+
+    pub(crate) fn finish(mut self) -> std::vec::Vec<u8> {
+        // pick the value in range with the most trailing zeros, the decoder pads with zeros
+        for shift in (0..=32).rev() {
+            let mask = (1 << shift) - 1;
+            let value = (self.low + mask) & !mask;
+            if value < self.low + self.range as u64 {
+                self.low = value;
+                break;
+            }
+        }
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        // the first byte is always zero
+        self.out.remove(0);
+        let state = self.out.len() - 4;
+        while self.out.len() > state && self.out.last() == Some(&0) {
+            self.out.pop();
+        }
+        self.out
+    }
+}
+
+#[cfg(feature = "dec")]
+#[derive(Debug, Clone)]
+pub(crate) struct RangeDecoder<'a> {
+    buf: &'a [u8],
+    range: u32,
+    code: u32,
+    /// Zero bytes read past the end of the buffer, at most the four bytes of the coder state
+    padding: u8,
+}
+
+#[cfg(feature = "dec")]
+impl<'a> RangeDecoder<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        let mut rc = Self {
+            buf,
+            range: u32::MAX,
+            code: 0,
+            padding: 0,
+        };
+        for _ in 0..4 {
+            // can't fail, the whole state may be padding
+            rc.code = rc.code << 8 | rc.next_byte().unwrap_or_default() as u32;
+        }
+        rc
+    }
+
+    fn next_byte(&mut self) -> Result<u8, ParseError> {
+        match self.buf.split_first() {
+            Some((&byte, rest)) => {
+                self.buf = rest;
+                Ok(byte)
+            }
+            // trailing zeros are trimmed by the encoder
+            None if self.padding < 4 => {
+                self.padding += 1;
+                Ok(0)
+            }
+            None => Err(ParseError::Truncated),
+        }
+    }
+
+    pub(crate) fn decode_bit(&mut self, prob: &mut u16) -> Result<bool, ParseError> {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        let bit = if self.code < bound {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+            false
+        } else {
+            self.code -= bound;
+            self.range -= bound;
+            *prob -= *prob >> MOVE_BITS;
+            true
+        };
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = self.code << 8 | self.next_byte()? as u32;
+        }
+        Ok(bit)
+    }
+
+    /// Bytes left after the last decoded bit
+    pub(crate) fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+}
@@ -1,28 +1,115 @@
 #![cfg_attr(not(feature = "std"), no_std)]
+#[cfg(feature = "dec")]
 use core::convert::TryFrom;
 
 #[cfg(feature = "dec")]
 pub mod dec;
 #[cfg(feature = "enc")]
 pub mod enc;
+#[cfg(any(feature = "dec", feature = "enc"))]
+mod entropy;
+#[cfg(feature = "scan")]
+pub mod scan;
 
 #[cfg(test)]
 pub mod tests;
 
 mod utils {
+    #[cfg_attr(not(any(feature = "dec", feature = "enc")), allow(dead_code))]
     pub(crate) fn next_pos(pos: &mut crate::Position) -> Option<()> {
-        Some(if let Some(p) = pos.last_mut() {
+        if let Some(p) = pos.last_mut() {
             if *p + 1 > 3 {
                 pos.pop()?;
                 next_pos(pos);
             } else {
                 *p += 1
             }
+        }
+        Some(())
+    }
+
+    /// Splits the pixels `start..end` by byte, into `(byte, first bit, end bit)`
+    #[cfg_attr(
+        not(any(feature = "dec", feature = "enc", feature = "scan")),
+        allow(dead_code)
+    )]
+    pub(crate) fn spans(start: usize, end: usize) -> impl Iterator<Item = (usize, usize, usize)> {
+        (start / 8..=(end - 1) / 8).map(move |byte| {
+            let lo = if byte == start / 8 { start % 8 } else { 0 };
+            let hi = if byte == (end - 1) / 8 {
+                (end - 1) % 8 + 1
+            } else {
+                8
+            };
+            (byte, lo, hi)
         })
     }
 }
 
-type Position = heapless::Vec<u8, 7>;
+/// Maximum depth of a tree, limits the root square to 1024x1024 pixels
+pub const MAX_DEPTH: usize = 10;
+
+type Position = heapless::Vec<u8, MAX_DEPTH>;
+
+/// Real dimensions of an image.
+///
+/// The tree covering it is padded to the enclosing power-of-two square, decoders use the real
+/// dimensions to clip the padding away.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Resolution {
+    width: u16,
+    height: u16,
+}
+
+impl Resolution {
+    /// Returns `None` if either side is zero or the root square would exceed `MAX_DEPTH`.
+    pub fn new(width: u16, height: u16) -> Option<Self> {
+        let res = Self { width, height };
+        if width == 0 || height == 0 || res.depth() as usize > MAX_DEPTH {
+            None
+        } else {
+            Some(res)
+        }
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// Side of the root square in pixels
+    pub fn side(&self) -> u32 {
+        (self.width.max(self.height) as u32).next_power_of_two()
+    }
+
+    /// Depth of the tree, how many times the root square can be split before reaching a pixel
+    pub fn depth(&self) -> u8 {
+        self.side().trailing_zeros() as u8
+    }
+
+    /// Length of a row in a row-major, 8 pixels per byte buffer
+    pub fn stride(&self) -> usize {
+        (self.width as usize).div_ceil(8)
+    }
+
+    /// Length in bytes of a row-major, 8 pixels per byte buffer
+    pub fn buf_len(&self) -> usize {
+        self.stride() * self.height as usize
+    }
+}
+
+impl Default for Resolution {
+    /// 128x64, the size of the framebuffer used by older versions of the crate
+    fn default() -> Self {
+        Self {
+            width: 128,
+            height: 64,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct FrameMeta {
@@ -32,6 +119,11 @@ pub struct FrameMeta {
     pub partial: bool,
     /// Tells decoder to flush the frambuffer after this frame
     pub display: bool,
+    /// Leaves toggle the pixels they cover instead of setting them, `active_feature` is
+    /// ignored.
+    ///
+    /// Used in P-frames encoding the XOR of two frames, see `QuadTree::store_as_xor`.
+    pub xor: bool,
 }
 
 impl FrameMeta {
@@ -40,27 +132,34 @@ impl FrameMeta {
             active_feature,
             partial,
             display,
+            xor: false,
         }
     }
 }
 
-impl Into<u8> for FrameMeta {
-    fn into(self) -> u8 {
-        self.active_feature as u8 | (self.partial as u8) << 1 | (self.display as u8) << 2
+impl From<FrameMeta> for u8 {
+    fn from(meta: FrameMeta) -> u8 {
+        meta.active_feature as u8
+            | (meta.partial as u8) << 1
+            | (meta.display as u8) << 2
+            | (meta.xor as u8) << 3
     }
 }
 
+#[cfg(feature = "dec")]
 impl TryFrom<u8> for FrameMeta {
     type Error = dec::ParseError;
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        if value <= 0b111 {
+        if value <= 0b1111 {
             let active_feature = value & 1 == 1;
             let partial = (value >> 1) & 1 == 1;
             let display = (value >> 2) & 1 == 1;
+            let xor = (value >> 3) & 1 == 1;
             Ok(Self {
                 active_feature,
                 partial,
                 display,
+                xor,
             })
         } else {
             Err(Self::Error::InvalidHeader)
@@ -68,10 +167,143 @@ impl TryFrom<u8> for FrameMeta {
     }
 }
 
+/// Wire format of the trees making up a frame
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TreeFormat {
+    /// Linear list of leaves, see `QuadTree::store_packed`
+    Packed = 1,
+    /// Two bits per node, see `QuadTree::collect_compact`
+    Compact = 2,
+    /// Compact with 4x4 bitmap leaves, see `QuadTree::collect_compact_bitmap`
+    CompactBitmap = 3,
+    /// Compact with range coded nodes, see `QuadTree::collect_compact_entropy`
+    CompactEntropy = 4,
+}
+
+#[cfg(feature = "dec")]
+impl TryFrom<u8> for TreeFormat {
+    type Error = dec::ParseError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::Packed),
+            2 => Ok(Self::Compact),
+            3 => Ok(Self::CompactBitmap),
+            4 => Ok(Self::CompactEntropy),
+            _ => Err(Self::Error::UnsupportedFormat),
+        }
+    }
+}
+
+/// Header of a video container.
+///
+/// All fields are little endian:  
+/// `magic: [u8; 4]` always `MQTV`  
+/// `version: u8` version of the container  
+/// `format: u8` tree format of the frames  
+/// `width: u16`, `height: u16`  
+/// `frame_rate: u16` frames per second  
+/// `frame_count: u32` zero if unknown  
+/// `i_frame_interval: u16`  
+/// `flags: u8` bit 0 is set when the stream ends with a seek index, bit 1 when the frames
+/// start with a frame header
+///
+/// The frames follow, each prefixed with its `u16` length.
+/// Streams with frame headers have version 2, see `dec::Motion` for the frame header.
+///
+/// The seek index lists every I-frame as a `(frame: u32, offset: u32)` pair,
+/// with the offset counted from the first frame, followed by the number of pairs as `u32`
+/// and the `MQTI` magic.
+/// The first frame is always an I-frame and frames are never empty, so the index starts with
+/// two zero bytes where a frame length would be, which is how streaming readers find it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct VideoHeader {
+    pub format: TreeFormat,
+    pub resolution: Resolution,
+    /// Frames per second
+    pub frame_rate: u16,
+    /// Number of frames in the stream, zero if unknown
+    pub frame_count: u32,
+    /// Inclusive interval between I-frames, the longest one if the encoder also starts them
+    /// at scene cuts
+    pub i_frame_interval: u16,
+    /// The stream ends with a seek index
+    pub indexed: bool,
+    /// Frames start with a header that can move or copy parts of the previous frame, see
+    /// `dec::Motion`
+    pub motion: bool,
+}
+
+impl VideoHeader {
+    pub const MAGIC: [u8; 4] = *b"MQTV";
+    pub const INDEX_MAGIC: [u8; 4] = *b"MQTI";
+    /// Latest version of the container, older versions are still written when they suffice
+    pub const VERSION: u8 = 2;
+    /// Length of the header in bytes
+    pub const LEN: usize = 19;
+
+    pub fn new(
+        format: TreeFormat,
+        resolution: Resolution,
+        frame_rate: u16,
+        i_frame_interval: u16,
+    ) -> Self {
+        Self {
+            format,
+            resolution,
+            frame_rate,
+            frame_count: 0,
+            i_frame_interval,
+            indexed: false,
+            motion: false,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut out = [0; Self::LEN];
+        out[..4].copy_from_slice(&Self::MAGIC);
+        out[4] = if self.motion { 2 } else { 1 };
+        out[5] = self.format as u8;
+        out[6..8].copy_from_slice(&self.resolution.width().to_le_bytes());
+        out[8..10].copy_from_slice(&self.resolution.height().to_le_bytes());
+        out[10..12].copy_from_slice(&self.frame_rate.to_le_bytes());
+        out[12..16].copy_from_slice(&self.frame_count.to_le_bytes());
+        out[16..18].copy_from_slice(&self.i_frame_interval.to_le_bytes());
+        out[18] = self.indexed as u8 | (self.motion as u8) << 1;
+        out
+    }
+
+    #[cfg(feature = "dec")]
+    /// Parses the header at the start of the buffer.
+    pub fn parse(buf: &[u8]) -> Result<Self, dec::ParseError> {
+        use dec::ParseError;
+
+        let buf = match buf.get(..Self::LEN) {
+            Some(b) if b[..4] == Self::MAGIC => b,
+            _ => return Err(ParseError::InvalidHeader),
+        };
+        if !(1..=Self::VERSION).contains(&buf[4]) {
+            return Err(ParseError::UnsupportedFormat);
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+
+        Ok(Self {
+            format: TreeFormat::try_from(buf[5])?,
+            resolution: Resolution::new(u16_at(6), u16_at(8)).ok_or(ParseError::InvalidHeader)?,
+            frame_rate: u16_at(10),
+            frame_count: u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]),
+            i_frame_interval: u16_at(16),
+            indexed: buf[18] & 1 == 1,
+            motion: buf[4] >= 2 && buf[18] & 2 == 2,
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Leaf {
     pub data: LeafData,
     pos: Position,
+    /// Depth of the tree the leaf belongs to, the side of its square is `1 << (root - depth)`
+    root: u8,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -81,14 +313,37 @@ pub enum LeafData {
 }
 
 impl Leaf {
-    pub fn new(data: LeafData, pos: Position) -> Self {
-        Self { data, pos }
+    pub fn new(data: LeafData, pos: Position, root: u8) -> Self {
+        Self { data, pos, root }
     }
 
     pub fn depth(&self) -> usize {
         self.pos.len()
     }
 
+    /// Depth of the tree the leaf belongs to
+    pub fn root_depth(&self) -> u8 {
+        self.root
+    }
+
+    /// Side of the square covered by the leaf, in pixels
+    pub fn side(&self) -> u32 {
+        1 << (self.root as usize - self.depth())
+    }
+
+    /// Top left corner of the square covered by the leaf
+    pub fn origin(&self) -> (u32, u32) {
+        let root = self.root as usize;
+        let mut x = 0u32;
+        let mut y = 0u32;
+
+        for (i, p) in self.pos.iter().enumerate() {
+            x |= ((p & 1) as u32) << (root - 1 - i);
+            y |= ((p >> 1) as u32) << (root - 1 - i);
+        }
+        (x, y)
+    }
+
     pub fn contains(&self, other: &Self) -> bool {
         if self.depth() > other.depth() {
             return false;
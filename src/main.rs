@@ -1,14 +1,33 @@
 use std::{
+    convert::TryFrom,
     error::Error,
     fs::File,
-    io::{self, stdin, stdout, BufReader, BufWriter, Read, Write},
+    io::{self, stdin, stdout, BufRead, BufReader, BufWriter, Read, Write},
 };
 
 use argh::FromArgs;
-use monochrome_quadtree::enc::{video::{VideoEncoder, EncoderV1, EncoderV2}, QuadTree};
+use monochrome_quadtree::{
+    dec::{
+        export::{self, Rendered},
+        video::{Video, VideoSlice},
+        AnyDecoder, LeafParserV1, LeafParserV2, LeafParserV3, LeafParserV4, ParseError, ParseFrame,
+    },
+    enc::{
+        input::{self, Fit},
+        lossy::{Lossy, PixelError},
+        mono::Binarize,
+        rate::{RateLimit, RateReport},
+        video::{
+            self, EncoderV1, EncoderV2, EncoderV3, EncoderV4, MotionEncoder, PFrames,
+            TemporalLossy, VideoEncoder,
+        },
+        QuadTree,
+    },
+    Resolution, TreeFormat, VideoHeader,
+};
 
 #[derive(FromArgs)]
-/// Encode one or more frames using linear quadtrees
+/// Encode one or more frames using linear quadtrees, or decode them back
 struct Encode {
     #[argh(option, short = 'v', default = "1")]
     /// tree wire format version
@@ -22,6 +41,7 @@ struct Encode {
 enum SubCommands {
     Frame(Frame),
     Sequence(Sequence),
+    Decode(Decode),
 }
 
 #[derive(FromArgs)]
@@ -29,11 +49,33 @@ enum SubCommands {
 /// Compress a single frame
 struct Frame {
     #[argh(option, short = 'i', default = "String::from(\"-\")")]
-    /// input file, defaults to standard input
+    /// input file, a raw bitmap or a PBM/PGM, BMP or PNG image, defaults to standard input
     input: String,
     #[argh(option, short = 'o', default = "String::from(\"-\")")]
     /// output file, defaults to standard output
     output: String,
+    #[argh(option, short = 'W', default = "128")]
+    /// width of the frame in pixels
+    width: u16,
+    #[argh(option, short = 'H', default = "64")]
+    /// height of the frame in pixels
+    height: u16,
+    #[argh(
+        option,
+        short = 'b',
+        default = "Binarize::default()",
+        from_str_fn(parse_binarize)
+    )]
+    /// how images are made monochrome: threshold[=N] (default 128), otsu, bayer or
+    /// floyd-steinberg
+    binarize: Binarize,
+    #[argh(option, default = "Lossy::default()", from_str_fn(parse_lossy))]
+    /// wrong pixels a square may have and still become a single leaf, one count for every
+    /// depth or a comma separated list starting from the root, lossless by default
+    lossy: Lossy,
+    #[argh(switch)]
+    /// print the wrong pixels and PSNR of every frame to standard error
+    report: bool,
 }
 
 #[derive(FromArgs)]
@@ -41,7 +83,8 @@ struct Frame {
 /// Compress multiple contiguous frames
 struct Sequence {
     #[argh(option, short = 'i', default = "String::from(\"-\")")]
-    /// input file, defaults to standard input
+    /// input file, concatenated raw bitmaps, an animated GIF or a Y4M video, defaults to
+    /// standard input
     input: String,
     #[argh(option, short = 'o', default = "String::from(\"-\")")]
     /// output file, defaults to standard output
@@ -50,8 +93,133 @@ struct Sequence {
     /// number of frames to process
     frames: Option<u32>,
     #[argh(option, short = 'k', default = "60")]
-    /// inclusive interval between I-frames
+    /// inclusive interval between I-frames, the longest one with --scene-cut
     i_frame_interval: u16,
+    #[argh(option, short = 'W', default = "128")]
+    /// width of the frames in pixels
+    width: u16,
+    #[argh(option, short = 'H', default = "64")]
+    /// height of the frames in pixels
+    height: u16,
+    #[argh(option, short = 'r')]
+    /// frames per second, recorded in the header, defaults to the rate of the input or 30
+    frame_rate: Option<u16>,
+    #[argh(
+        option,
+        short = 'b',
+        default = "Binarize::default()",
+        from_str_fn(parse_binarize)
+    )]
+    /// how GIF and Y4M frames are made monochrome: threshold[=N] (default 128), otsu, bayer
+    /// or floyd-steinberg
+    binarize: Binarize,
+    #[argh(option, default = "Fit::default()", from_str_fn(parse_fit))]
+    /// how GIF and Y4M frames of another size are fitted: scale (default) or crop
+    fit: Fit,
+    #[argh(option, default = "Lossy::default()", from_str_fn(parse_lossy))]
+    /// wrong pixels a square may have and still become a single leaf, one count for every
+    /// depth or a comma separated list starting from the root, lossless by default
+    lossy: Lossy,
+    #[argh(option, default = "Lossy::default()", from_str_fn(parse_lossy))]
+    /// changed pixels a square may leave out of a P-frame, same format as --lossy, the
+    /// decoder catches up once the square changes more or on the next I-frame
+    temporal: Lossy,
+    #[argh(option)]
+    /// send scrolling, panning and moving sprites as shifts of the previous frame and copies
+    /// of its squares, searching up to this many pixels in each direction
+    motion: Option<u16>,
+    #[argh(option)]
+    /// start an I-frame instead of a P-frame that would take more than this percentage of
+    /// the frame on its own, restarting the interval
+    scene_cut: Option<u8>,
+    #[argh(option)]
+    /// keep every frame under this many bytes, losing pixels or postponing I-frames when it
+    /// doesn't fit
+    max_frame_bytes: Option<u16>,
+    #[argh(option)]
+    /// keep the frames under this many bytes per second on average, at the frame rate of the
+    /// header
+    bitrate: Option<u32>,
+    #[argh(switch)]
+    /// store the P-frames of format 1 as the XOR of consecutive frames, a single leaf list
+    /// that older decoders can't draw
+    xor: bool,
+    #[argh(switch)]
+    /// print the wrong pixels and PSNR of every frame to standard error
+    report: bool,
+    #[argh(switch)]
+    /// write a bare stream of frames without the container header, decoding it needs the same
+    /// resolution and format
+    raw: bool,
+    #[argh(switch)]
+    /// end the container with a seek index of the I-frames
+    index: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "decode")]
+/// Decode frames back to raw bitmaps
+struct Decode {
+    #[argh(subcommand)]
+    subs: DecodeCommands,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum DecodeCommands {
+    Frame(DecodeFrame),
+    Sequence(DecodeSequence),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "frame")]
+/// Decompress a single frame
+struct DecodeFrame {
+    #[argh(option, short = 'i', default = "String::from(\"-\")")]
+    /// input file, defaults to standard input
+    input: String,
+    #[argh(option, short = 'o', default = "String::from(\"-\")")]
+    /// output file, defaults to standard output
+    output: String,
+    #[argh(option, short = 'W', default = "128")]
+    /// width of the frame in pixels
+    width: u16,
+    #[argh(option, short = 'H', default = "64")]
+    /// height of the frame in pixels
+    height: u16,
+    #[argh(switch)]
+    /// write a PBM image instead of the raw bitmap
+    pbm: bool,
+    #[argh(switch)]
+    /// write a PNG image instead of the raw bitmap
+    png: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "sequence")]
+/// Decompress multiple frames, from a container or a bare stream
+struct DecodeSequence {
+    #[argh(option, short = 'i', default = "String::from(\"-\")")]
+    /// input file, defaults to standard input
+    input: String,
+    #[argh(option, short = 'o', default = "String::from(\"-\")")]
+    /// output file, defaults to standard output
+    output: String,
+    #[argh(option, short = 'W', default = "128")]
+    /// width of the frames of a bare stream in pixels
+    width: u16,
+    #[argh(option, short = 'H', default = "64")]
+    /// height of the frames of a bare stream in pixels
+    height: u16,
+    #[argh(switch)]
+    /// write concatenated PBM images instead of the raw bitmaps
+    pbm: bool,
+    #[argh(switch)]
+    /// write an animated GIF instead of the raw bitmaps
+    gif: bool,
+    #[argh(option, short = 'r')]
+    /// frames per second of the GIF, defaults to the rate in the header or 30
+    frame_rate: Option<u16>,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -60,11 +228,80 @@ fn main() -> Result<(), Box<dyn Error>> {
     match args.subs {
         SubCommands::Frame(s) => frame(s, args.version),
         SubCommands::Sequence(s) => sequence(s, args.version),
+        SubCommands::Decode(d) => match d.subs {
+            DecodeCommands::Frame(s) => decode_frame(s, args.version),
+            DecodeCommands::Sequence(s) => decode_sequence(s, args.version),
+        },
     }
     .map_err(|e| e.into())
 }
 
-fn match_input(i: &str) -> Box<dyn Read> {
+fn parse_binarize(s: &str) -> Result<Binarize, String> {
+    match s.split_once('=') {
+        None if s == "threshold" => Ok(Binarize::default()),
+        Some(("threshold", t)) => t
+            .parse()
+            .map(Binarize::Threshold)
+            .map_err(|_| format!("Invalid threshold {}", t)),
+        None if s == "otsu" => Ok(Binarize::Otsu),
+        None if s == "bayer" => Ok(Binarize::Bayer),
+        None if s == "floyd-steinberg" => Ok(Binarize::FloydSteinberg),
+        _ => Err(format!("Unknown conversion {}", s)),
+    }
+}
+
+fn parse_lossy(s: &str) -> Result<Lossy, String> {
+    let counts = s
+        .split(',')
+        .map(|c| c.parse().map_err(|_| format!("Invalid pixel count {}", c)))
+        .collect::<Result<Vec<u32>, _>>()?;
+    match counts[..] {
+        [count] => Ok(Lossy::global(count)),
+        _ => Ok(Lossy::per_depth(&counts)),
+    }
+}
+
+fn report(frame: usize, error: &PixelError) {
+    eprintln!(
+        "frame {}: {} wrong pixels of {}, PSNR {:.2} dB",
+        frame,
+        error.wrong,
+        error.total,
+        error.psnr()
+    );
+}
+
+/// Warns about the frames that lost quality or didn't fit the rate limit.
+fn report_rate(frame: usize, rate: &RateReport) {
+    if rate.over_budget() {
+        eprintln!(
+            "frame {}: {} bytes over the budget of {}",
+            frame, rate.size, rate.budget
+        );
+    } else if rate.degraded() {
+        eprintln!(
+            "frame {}: degraded to fit {} bytes, lossy allowance {}{}",
+            frame,
+            rate.budget,
+            rate.level,
+            if rate.postponed_i_frame {
+                ", I-frame postponed"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+fn parse_fit(s: &str) -> Result<Fit, String> {
+    match s {
+        "scale" => Ok(Fit::Scale),
+        "crop" => Ok(Fit::Crop),
+        _ => Err(format!("Unknown fit {}", s)),
+    }
+}
+
+fn match_input(i: &str) -> Box<dyn BufRead> {
     match i {
         // TODO: check if stdio is a tty
         "-" => Box::new(BufReader::new(stdin())),
@@ -84,42 +321,290 @@ fn frame(args: Frame, version: u8) -> io::Result<()> {
     let mut input = match_input(&args.input);
     let mut output = match_output(&args.output);
 
-    let mut buf = [0; 1024];
-    input.read_exact(&mut buf)?;
+    let res = Resolution::new(args.width, args.height)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid resolution"))?;
+    if version == 1 && res.depth() > 7 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The packed format only fits images up to 128 pixels per side",
+        ));
+    }
+    let mut data = Vec::new();
+    input.read_to_end(&mut data)?;
+
+    let mut buf = match input::detect(&data) {
+        Some(format) => input::load_image(&data, format, res, args.binarize)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        None => {
+            data.truncate(res.buf_len());
+            if data.len() < res.buf_len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("Expected a raw bitmap of {} bytes", res.buf_len()),
+                ));
+            }
+            data
+        }
+    };
+    let error = args.lossy.apply(&mut buf, res);
+    if args.report {
+        report(0, &error);
+    }
 
     match version {
-        1 => { QuadTree::from_128x64(&buf, true).store_packed(output)?; },
-        2 => { output.write_all(QuadTree::from_128x64(&buf, false).collect_compact().unwrap().as_raw_slice())?; },
-        _ => panic!("Invalid format version, valid versions are 1 and 2"),
+        1 => { QuadTree::from_buf(&buf, res, true).store_packed(output)?; },
+        2 => { output.write_all(QuadTree::from_buf(&buf, res, false).collect_compact().unwrap().as_raw_slice())?; },
+        3 => {
+            let tree = QuadTree::from_buf(&buf, res, false);
+            output.write_all(tree.collect_compact_bitmap(&tree).as_raw_slice())?;
+        }
+        4 => {
+            let tree = QuadTree::from_buf(&buf, res, false);
+            output.write_all(&tree.collect_compact_entropy().unwrap())?;
+        }
+        _ => panic!("Invalid format version, valid versions are 1 to 4"),
     }
     Ok(())
 }
 
 fn sequence(args: Sequence, version: u8) -> io::Result<()> {
-    let input = match_input(&args.input);
-    let output = match_output(&args.output);
-
     match version {
-        1 => seq_v1(output, input, args),
-        2 => seq_v2(output, input, args),
-        _ => panic!("Invalid format version, valid versions are 1 and 2"),
+        1 => {
+            let p_frames = if args.xor {
+                PFrames::Xor
+            } else {
+                PFrames::Split
+            };
+            seq_with(args, EncoderV1::new(p_frames))
+        }
+        _ if args.xor => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--xor is only supported by format 1",
+        )),
+        2 => seq_with(args, EncoderV2::default()),
+        3 => seq_with(args, EncoderV3::default()),
+        4 => seq_with(args, EncoderV4::default()),
+        _ => panic!("Invalid format version, valid versions are 1 to 4"),
     }
 }
 
-fn seq_v1(output: Box<dyn Write>, mut input: Box<dyn Read>, args: Sequence) -> io::Result<()> {
-    let mut enc = VideoEncoder::<_, EncoderV1>::new(output, args.i_frame_interval);
-    Ok(if let Some(f) = args.frames {
-        io::copy(&mut input.take(f as u64 * 1024), &mut enc)?;
-    } else {
-        io::copy(&mut input, &mut enc)?;
-    })
+/// Wraps the encoder of the format in the ones asked for on the command line.
+fn seq_with<E: video::Encode>(args: Sequence, encoder: E) -> io::Result<()> {
+    let encoder = TemporalLossy::new(encoder, args.temporal);
+    match args.motion {
+        // the shifts are recorded in frame headers, which bare streams can't announce
+        Some(_) if args.raw => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--motion needs the container header",
+        )),
+        Some(range) => seq(args, MotionEncoder::new(encoder, range)),
+        None => seq(args, encoder),
+    }
+}
+
+/// Frames of a sequence, as raw bitmaps or decoded from an animation
+enum SequenceInput {
+    Raw(Box<dyn BufRead>),
+    Frames(input::Frames<'static>),
 }
 
-fn seq_v2(output: Box<dyn Write>, mut input: Box<dyn Read>, args: Sequence) -> io::Result<()> {
-    let mut enc = VideoEncoder::<_, EncoderV2>::new(output, args.i_frame_interval);
-    Ok(if let Some(f) = args.frames {
-        io::copy(&mut input.take(f as u64 * 1024), &mut enc)?;
+fn seq<E: video::Encode>(args: Sequence, encoder: E) -> io::Result<()> {
+    let mut input = match_input(&args.input);
+    let output = match_output(&args.output);
+
+    if args.raw && args.index {
+        // the flag announcing the index is in the header, bare streams would read it as frames
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--index needs the container header",
+        ));
+    }
+    let res = Resolution::new(args.width, args.height)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid resolution"))?;
+    let frame_len = res.buf_len() as u64;
+
+    let input = match input::detect_sequence(input.fill_buf()?) {
+        Some(format) => SequenceInput::Frames(
+            input::Frames::new(input, format, res, args.fit, args.binarize)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        ),
+        None => SequenceInput::Raw(input),
+    };
+
+    let frame_rate = args
+        .frame_rate
+        .or(match &input {
+            SequenceInput::Frames(f) => f.frame_rate(),
+            SequenceInput::Raw(_) => None,
+        })
+        .unwrap_or(30);
+    let rate_limit = match (args.max_frame_bytes, args.bitrate) {
+        (Some(_), Some(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only one of --max-frame-bytes and --bitrate can be used",
+            ))
+        }
+        (Some(max), None) => Some(RateLimit::Frame(max as usize)),
+        (None, Some(bytes_per_second)) => Some(RateLimit::Average {
+            bytes_per_second,
+            frame_rate,
+        }),
+        (None, None) => None,
+    };
+
+    let mut enc = if args.raw {
+        VideoEncoder::<_, E>::with_resolution(output, res, args.i_frame_interval)?
     } else {
-        io::copy(&mut input, &mut enc)?;
-    })
+        let mut header = VideoHeader::new(E::FORMAT, res, frame_rate, args.i_frame_interval);
+        header.indexed = args.index;
+        header.frame_count = match (args.frames, args.input.as_str()) {
+            (Some(f), _) => f,
+            // the frames of animations aren't known before decoding them
+            (None, _) if matches!(input, SequenceInput::Frames(_)) => 0,
+            (None, "-") => 0,
+            // a trailing partial frame is padded and encoded as well
+            (None, path) => std::fs::metadata(path)?.len().div_ceil(frame_len) as u32,
+        };
+        VideoEncoder::<_, E>::with_header(output, header)?
+    };
+    enc.set_lossy(args.lossy);
+    enc.set_scene_cut(args.scene_cut);
+    enc.set_rate_limit(rate_limit);
+    *enc.encoder_mut() = encoder;
+
+    match (input, args.frames) {
+        (SequenceInput::Frames(frames), count) => {
+            for frame in frames.take(count.map_or(usize::MAX, |f| f as usize)) {
+                let frame = frame.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                enc.write_all(&frame)?;
+            }
+        }
+        (SequenceInput::Raw(input), Some(f)) => {
+            io::copy(&mut input.take(f as u64 * frame_len), &mut enc)?;
+        }
+        (SequenceInput::Raw(mut input), None) => {
+            io::copy(&mut input, &mut enc)?;
+        }
+    }
+    enc.flush()?;
+    if args.report {
+        for (i, error) in enc.errors().iter().enumerate() {
+            report(i, error);
+        }
+    }
+    for (i, rate) in enc.rate_reports().iter().enumerate() {
+        report_rate(i, rate);
+    }
+    enc.finish()?.flush()
+}
+
+fn invalid_data(e: ParseError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn tree_format(version: u8) -> io::Result<TreeFormat> {
+    TreeFormat::try_from(version).map_err(invalid_data)
+}
+
+fn export_error(e: export::ExportError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn decode_frame(args: DecodeFrame, version: u8) -> io::Result<()> {
+    let mut input = match_input(&args.input);
+    let mut output = match_output(&args.output);
+
+    let res = Resolution::new(args.width, args.height)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid resolution"))?;
+    let mut data = Vec::new();
+    input.read_to_end(&mut data)?;
+
+    let dec = AnyDecoder::new(&data, tree_format(version)?, res).map_err(invalid_data)?;
+    let buf = export::render(&dec).map_err(invalid_data)?;
+
+    match (args.pbm, args.png) {
+        (false, false) => output.write_all(&buf)?,
+        (true, false) => export::write_pbm(&buf, res, &mut output)?,
+        (false, true) => {
+            export::write_png(&buf, res, &mut output).map_err(|e| export_error(e.into()))?
+        }
+        (true, true) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only one of --pbm and --png can be used",
+            ))
+        }
+    }
+    output.flush()
+}
+
+fn decode_sequence(args: DecodeSequence, version: u8) -> io::Result<()> {
+    let mut input = match_input(&args.input);
+    let output = match_output(&args.output);
+
+    let mut data = Vec::new();
+    input.read_to_end(&mut data)?;
+
+    // containers describe themselves, bare streams need the format version
+    if data.starts_with(&VideoHeader::MAGIC) {
+        let video = Video::new(&data).map_err(invalid_data)?;
+        let header = video.header();
+        let args = DecodeSequence {
+            frame_rate: args.frame_rate.or(Some(header.frame_rate)),
+            ..args
+        };
+        return write_frames(video.frames(), &args, output);
+    }
+    let res = Resolution::new(args.width, args.height)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid resolution"))?;
+    match tree_format(version)? {
+        TreeFormat::Packed => {
+            let frames = VideoSlice::<LeafParserV1>::with_resolution(&data, res);
+            write_frames(frames, &args, output)
+        }
+        TreeFormat::Compact => {
+            let frames = VideoSlice::<LeafParserV2>::with_resolution(&data, res);
+            write_frames(frames, &args, output)
+        }
+        TreeFormat::CompactBitmap => {
+            let frames = VideoSlice::<LeafParserV3>::with_resolution(&data, res);
+            write_frames(frames, &args, output)
+        }
+        TreeFormat::CompactEntropy => {
+            let frames = VideoSlice::<LeafParserV4>::with_resolution(&data, res);
+            write_frames(frames, &args, output)
+        }
+    }
+}
+
+fn write_frames<'a, D: ParseFrame<'a>>(
+    frames: VideoSlice<'a, D>,
+    args: &DecodeSequence,
+    mut output: impl Write,
+) -> io::Result<()> {
+    let res = frames.resolution();
+    match (args.pbm, args.gif) {
+        (true, true) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only one of --pbm and --gif can be used",
+            ))
+        }
+        (_, true) => {
+            let frame_rate = args.frame_rate.unwrap_or(30);
+            export::write_gif(frames, frame_rate, &mut output).map_err(export_error)?
+        }
+        (pbm, false) => {
+            for buf in Rendered::new(frames) {
+                let buf = buf.map_err(invalid_data)?;
+                if pbm {
+                    export::write_pbm(&buf, res, &mut output)?;
+                } else {
+                    output.write_all(&buf)?;
+                }
+            }
+        }
+    }
+    output.flush()
 }
@@ -0,0 +1,309 @@
+//! Encoders writing the tree of a framebuffer while scanning it, without building it.
+//!
+//! They work in `no_std` without an allocator: each square is checked straight from the
+//! row-major buffer, in the depth-first order of the tree, and the output goes into a `Sink`.
+//! The output is the same as `QuadTree::store_packed` and `QuadTree::collect_compact`,
+//! so the frames are I-frames.
+
+use crate::{utils::spans, FrameMeta, Resolution};
+use core::fmt;
+
+/// Output of the encoders, one byte at a time
+pub trait Sink {
+    /// Appends a byte, fails if the output is full.
+    fn push(&mut self, byte: u8) -> Result<(), ScanError>;
+}
+
+impl<const N: usize> Sink for heapless::Vec<u8, N> {
+    fn push(&mut self, byte: u8) -> Result<(), ScanError> {
+        heapless::Vec::push(self, byte).map_err(|_| ScanError::Full)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Sink for std::vec::Vec<u8> {
+    fn push(&mut self, byte: u8) -> Result<(), ScanError> {
+        std::vec::Vec::push(self, byte);
+        Ok(())
+    }
+}
+
+/// Fills a byte slice from the start
+#[derive(Debug)]
+pub struct SliceSink<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceSink<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    /// Bytes written so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The part of the slice written so far
+    pub fn into_written(self) -> &'a mut [u8] {
+        &mut self.buf[..self.len]
+    }
+}
+
+impl Sink for SliceSink<'_> {
+    fn push(&mut self, byte: u8) -> Result<(), ScanError> {
+        let slot = self.buf.get_mut(self.len).ok_or(ScanError::Full)?;
+        *slot = byte;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ScanError {
+    /// The sink ran out of space
+    Full,
+    /// The tree is too deep for the format
+    UnsupportedResolution,
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Full => "output full",
+            Self::UnsupportedResolution => "resolution not supported by the format",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ScanError {}
+
+/// Writes a frame in the packed format of `QuadTree::store_packed`, returns its length.
+///
+/// The buffer is row-major like the one of `QuadTree::from_buf`. It's scanned twice, to pick
+/// the color with the fewest leaves and then to write them.
+/// The format only fits images up to 128 pixels per side.
+///
+/// Panics if the buffer length doesn't match the resolution.
+pub fn store_packed<S: Sink>(
+    buf: &[u8],
+    resolution: Resolution,
+    sink: &mut S,
+) -> Result<usize, ScanError> {
+    let scan = Scan::new(buf, resolution);
+    if resolution.depth() > 7 {
+        return Err(ScanError::UnsupportedResolution);
+    }
+
+    // leaves of each color, bitmaps count for both
+    let mut counts = [0usize; 2];
+    scan.walk(true, &mut |square| {
+        match square.node {
+            Visit::Feature(color) => counts[color as usize] += 1,
+            Visit::Bitmap(_) => counts.iter_mut().for_each(|c| *c += 1),
+            Visit::Empty | Visit::Branch => (),
+        }
+        Ok(())
+    })?;
+    let active = counts[1] < counts[0];
+
+    sink.push(FrameMeta::new(active, false, true).into())?;
+    let mut len = 1;
+    scan.walk(true, &mut |square| {
+        let (code, bitmap) = match square.node {
+            Visit::Feature(color) if color == active => (square.depth, None),
+            // depth 6 marks bitmaps
+            Visit::Bitmap(bitmap) => (6, Some(bitmap)),
+            _ => return Ok(()),
+        };
+        let mut word = 0x8000 | (code as u16) << 12;
+        for i in 0..square.depth {
+            word |= (square.quadrant(i) as u16) << (10 - 2 * i);
+        }
+        let [high, low] = word.to_be_bytes();
+        sink.push(high)?;
+        len += 1;
+        if square.depth > 2 {
+            sink.push(low)?;
+            len += 1;
+        }
+        if let Some([a, b]) = bitmap {
+            sink.push(a)?;
+            sink.push(b)?;
+            len += 2;
+        }
+        Ok(())
+    })?;
+    Ok(len)
+}
+
+/// Writes a frame in the compact format of `QuadTree::collect_compact`, returns its length.
+///
+/// The last byte is padded with zeros.
+/// Panics if the buffer length doesn't match the resolution.
+pub fn store_compact<S: Sink>(
+    buf: &[u8],
+    resolution: Resolution,
+    sink: &mut S,
+) -> Result<usize, ScanError> {
+    let scan = Scan::new(buf, resolution);
+    let (mut byte, mut bits, mut len) = (0u8, 0, 0);
+    scan.walk(false, &mut |square| {
+        let code = match square.node {
+            Visit::Empty => 0b00,
+            Visit::Branch => 0b01,
+            Visit::Feature(color) => 0b10 | color as u8,
+            Visit::Bitmap(_) => unreachable!(),
+        };
+        byte |= code << (6 - bits);
+        bits += 2;
+        if bits == 8 {
+            sink.push(byte)?;
+            len += 1;
+            byte = 0;
+            bits = 0;
+        }
+        Ok(())
+    })?;
+    if bits > 0 {
+        sink.push(byte)?;
+        len += 1;
+    }
+    Ok(len)
+}
+
+/// Node of the tree, as the scan finds it
+enum Visit {
+    /// The square only covers padding
+    Empty,
+    /// The quadrants follow
+    Branch,
+    Feature(bool),
+    /// Row-major 4x4 pixels
+    Bitmap([u8; 2]),
+}
+
+struct Square {
+    x: u32,
+    y: u32,
+    depth: usize,
+    root: usize,
+    node: Visit,
+}
+
+impl Square {
+    /// Quadrant the square is in at `depth`, see `Leaf::origin`
+    fn quadrant(&self, depth: usize) -> u8 {
+        let bit = self.root - 1 - depth;
+        ((self.y >> bit & 1) << 1 | self.x >> bit & 1) as u8
+    }
+}
+
+struct Scan<'a> {
+    buf: &'a [u8],
+    resolution: Resolution,
+}
+
+impl<'a> Scan<'a> {
+    fn new(buf: &'a [u8], resolution: Resolution) -> Self {
+        assert_eq!(
+            buf.len(),
+            resolution.buf_len(),
+            "Buffer length doesn't match the resolution"
+        );
+        Self { buf, resolution }
+    }
+
+    /// Calls `visit` with every node, depth first.
+    ///
+    /// Non-uniform 4x4 squares become bitmaps if `bitmaps` is set.
+    fn walk<F>(&self, bitmaps: bool, visit: &mut F) -> Result<(), ScanError>
+    where
+        F: FnMut(Square) -> Result<(), ScanError>,
+    {
+        let root = self.resolution.depth() as usize;
+        self.walk_square(0, 0, 0, root, bitmaps, visit)
+    }
+
+    fn walk_square<F>(
+        &self,
+        x: u32,
+        y: u32,
+        depth: usize,
+        root: usize,
+        bitmaps: bool,
+        visit: &mut F,
+    ) -> Result<(), ScanError>
+    where
+        F: FnMut(Square) -> Result<(), ScanError>,
+    {
+        let side = 1 << (root - depth);
+        let node = if x >= self.resolution.width() as u32 || y >= self.resolution.height() as u32
+        {
+            Visit::Empty
+        } else if let Some(color) = self.uniform(x, y, side) {
+            Visit::Feature(color)
+        } else if side == 4 && bitmaps {
+            Visit::Bitmap(self.bitmap(x, y))
+        } else {
+            Visit::Branch
+        };
+        let branch = matches!(node, Visit::Branch);
+        visit(Square {
+            x,
+            y,
+            depth,
+            root,
+            node,
+        })?;
+
+        if branch {
+            let half = side / 2;
+            for (dx, dy) in [(0, 0), (half, 0), (0, half), (half, half)] {
+                self.walk_square(x + dx, y + dy, depth + 1, root, bitmaps, visit)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Color of the part of a square inside the image, if it's uniform.
+    ///
+    /// The padding repeats the closest pixel, so it doesn't change the outcome.
+    fn uniform(&self, x: u32, y: u32, side: u32) -> Option<bool> {
+        let x1 = (x + side).min(self.resolution.width() as u32) as usize;
+        let y1 = (y + side).min(self.resolution.height() as u32) as usize;
+        let color = self.pixel(x, y);
+        let stride = self.resolution.stride();
+        for row in self.buf.chunks_exact(stride).take(y1).skip(y as usize) {
+            for (byte, lo, hi) in spans(x as usize, x1) {
+                // most significant bit first
+                let mask = (0xff >> lo) & (0xff00u16 >> hi) as u8;
+                if row[byte] & mask != if color { mask } else { 0 } {
+                    return None;
+                }
+            }
+        }
+        Some(color)
+    }
+
+    fn bitmap(&self, x: u32, y: u32) -> [u8; 2] {
+        let mut bits = 0u16;
+        for i in 0..16 {
+            bits |= (self.pixel(x + i % 4, y + i / 4) as u16) << (15 - i);
+        }
+        bits.to_be_bytes()
+    }
+
+    /// Pixel of the image, clamped to its edges
+    fn pixel(&self, x: u32, y: u32) -> bool {
+        let x = x.min(self.resolution.width() as u32 - 1) as usize;
+        let y = y.min(self.resolution.height() as u32 - 1) as usize;
+        self.buf[y * self.resolution.stride() + x / 8] & 0x80 >> (x % 8) != 0
+    }
+}
@@ -1,11 +1,12 @@
 use crate::{
-    dec::{Decoder, LeafParserV2},
+    dec::{Decoder, FromBuf, LeafParserV2, LeafParserV3, LeafParserV4, ParseFrame},
     enc::{
         tests::EXPECTED_BYTES_COMPACT,
-        video::{Encode, EncoderV2},
+        video::{Encode, EncoderV2, EncoderV3, EncoderV4},
     },
 };
 use std::{
+    cmp::min,
     convert::{Infallible, TryFrom},
     fs::File,
     io::{Read, Write},
@@ -18,26 +19,51 @@ use embedded_graphics::{
 use embedded_graphics_simulator::{OutputSettingsBuilder, SimulatorDisplay};
 
 use crate::{
-    dec::{video::VideoSlice, LeafParserV1},
+    dec::{
+        raw::{Framebuffer, Layout},
+        stream::{ReadError, VideoStream},
+        video::{Video, VideoSlice},
+        LeafParserV1,
+    },
     enc::{
+        lossy::{Lossy, PixelError},
+        mono::Binarize,
+        rate::{RateLimit, RateReport},
         tests::{BUF, EXPECTED_BYTES_LINEAR},
-        video::{EncoderV1, VideoEncoder},
+        video::{EncoderV1, MotionEncoder, VideoEncoder},
         QuadTree,
     },
-    FrameMeta,
+    FrameMeta, Resolution, TreeFormat, VideoHeader,
 };
 
-const WIDTH: u32 = 128;
-const HEIGHT: u32 = 64;
-
-#[derive(Default)]
+/// Stores pixels in the same layout as the encoder input
 struct DumpableDisplay {
-    buf: BitArr!(for 8192, in u8, Msb0),
+    buf: BitVec<u8, Msb0>,
+    res: Resolution,
+}
+
+impl DumpableDisplay {
+    fn new(res: Resolution) -> Self {
+        Self {
+            buf: BitVec::repeat(false, res.buf_len() * 8),
+            res,
+        }
+    }
+
+    fn stride(&self) -> usize {
+        self.res.stride() * 8
+    }
+}
+
+impl Default for DumpableDisplay {
+    fn default() -> Self {
+        Self::new(Resolution::default())
+    }
 }
 
 impl OriginDimensions for DumpableDisplay {
     fn size(&self) -> Size {
-        Size::new(WIDTH, HEIGHT)
+        Size::new(self.res.width() as u32, self.res.height() as u32)
     }
 }
 
@@ -51,7 +77,7 @@ impl DrawTarget for DumpableDisplay {
     {
         let bb = self.bounding_box();
         for Pixel(Point { x, y }, col) in pixels.into_iter().filter(|p| bb.contains(p.0)) {
-            let index = (y as u32 * WIDTH + x as u32) as usize;
+            let index = y as usize * self.stride() + x as usize;
             let col = if col.is_on() { 1u8 } else { 0 };
             self.buf[index..=index].store(col);
         }
@@ -70,7 +96,7 @@ impl DrawTarget for DumpableDisplay {
         let width = width as usize;
 
         for row_height in y..y + height {
-            let base = WIDTH as usize * row_height + x;
+            let base = self.stride() * row_height + x;
             self.buf[base..base + width].store(col);
         }
         Ok(())
@@ -83,10 +109,15 @@ fn framemeta_pack_then_parse() {
     for a in foo {
         for b in foo {
             for c in foo {
-                let left = FrameMeta::new(a, b, c);
-                let int: u8 = left.into();
-                let right = FrameMeta::try_from(int).unwrap();
-                assert_eq!(left, right)
+                for xor in foo {
+                    let left = FrameMeta {
+                        xor,
+                        ..FrameMeta::new(a, b, c)
+                    };
+                    let int: u8 = left.into();
+                    let right = FrameMeta::try_from(int).unwrap();
+                    assert_eq!(left, right)
+                }
             }
         }
     }
@@ -110,6 +141,68 @@ fn enc_then_draw_v1() {
     assert_eq!(BUF, display.buf.as_raw_slice())
 }
 
+#[test]
+fn edit_then_draw_v1() {
+    use std::io::ErrorKind;
+
+    let res = Resolution::default();
+    let mut expected = DumpableDisplay::default();
+    for (x, y) in [(5, 5), (6, 5), (100, 40), (127, 63)] {
+        expected.buf.set(y * 128 + x, true);
+    }
+    let edit = |tree: &mut QuadTree| {
+        tree.fill_rect(0, 0, 8, 8, true);
+        tree.fill_rect(0, 0, 8, 8, false);
+        for (x, y) in [(5, 5), (6, 5), (100, 40), (127, 63)] {
+            tree.set_pixel(x, y, true);
+        }
+    };
+
+    // single pixels are only stored in bitmaps
+    let mut tree = QuadTree::new(res);
+    edit(&mut tree);
+    let err = tree.store_packed(Vec::new()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let mut tree = QuadTree::new(res);
+    tree.use_bitmap = true;
+    edit(&mut tree);
+    let mut out = Vec::new();
+    tree.store_packed(&mut out).unwrap();
+
+    let mut display = DumpableDisplay::default();
+    let dec = LeafParserV1::new(&out).unwrap();
+    dec.drawable().draw(&mut display).unwrap();
+    assert_eq!(expected.buf, display.buf);
+}
+
+#[test]
+fn combine_then_draw_v1() {
+    use std::io::ErrorKind;
+
+    let a = synthetic_frames(Resolution::default(), 1);
+    let b: Vec<u8> = BUF.iter().map(|b| b.rotate_left(3)).collect();
+    let combined = |use_bitmap| {
+        let tree_a = QuadTree::from_buf(&a, Resolution::default(), use_bitmap);
+        let tree_b = QuadTree::from_buf(&b, Resolution::default(), use_bitmap);
+        tree_a
+            .xor(&tree_b)
+            .union(&tree_a.invert().intersection(&tree_b))
+    };
+
+    // single pixels are only stored in bitmaps
+    let err = combined(false).store_packed(Vec::new()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let mut out = Vec::new();
+    combined(true).store_packed(&mut out).unwrap();
+    let mut display = DumpableDisplay::default();
+    let dec = LeafParserV1::new(&out).unwrap();
+    dec.drawable().draw(&mut display).unwrap();
+    let expected: Vec<u8> = a.iter().zip(&b).map(|(a, b)| (a ^ b) | (!a & b)).collect();
+    assert_eq!(display.buf.as_raw_slice(), expected);
+}
+
 #[test]
 fn enc_then_draw_v2() {
     let tree = QuadTree::from_128x64(&BUF, false);
@@ -124,6 +217,198 @@ fn enc_then_draw_v2() {
     assert_eq!(BUF, display.buf.as_raw_slice())
 }
 
+#[test]
+fn enc_then_draw_v3() {
+    let tree = QuadTree::from_128x64(&BUF, false);
+    let compact = tree.collect_compact_bitmap(&tree);
+
+    let mut display = DumpableDisplay::default();
+    let dec = LeafParserV3::from_buf(compact.as_raw_slice()).unwrap();
+    dec.drawable().draw(&mut display).unwrap();
+
+    assert_eq!(BUF, display.buf.as_raw_slice())
+}
+
+#[test]
+fn enc_then_draw_v4() {
+    let tree = QuadTree::from_128x64(&BUF, false);
+    let coded = tree.collect_compact_entropy().unwrap();
+
+    let mut display = DumpableDisplay::default();
+    let dec = LeafParserV4::from_buf(&coded).unwrap();
+    dec.drawable().draw(&mut display).unwrap();
+
+    assert_eq!(BUF, display.buf.as_raw_slice());
+
+    let truncated = LeafParserV4::from_buf(&coded[..coded.len() / 2]).unwrap();
+    assert!(truncated.iter().any(|l| l.is_err()));
+}
+
+fn enc_then_draw_sized(res: Resolution) {
+    // diagonal stripes, so no sector is uniform
+    let buf: Vec<u8> = (0..res.buf_len())
+        .map(|i| 0b1100_1010u8.rotate_left((i / res.stride()) as u32))
+        .collect();
+    let mut expected = DumpableDisplay::new(res);
+    for y in 0..res.height() as usize {
+        let row = y * expected.stride();
+        let src = &buf.view_bits::<Msb0>()[row..];
+        expected.buf[row..row + res.width() as usize]
+            .copy_from_bitslice(&src[..res.width() as usize]);
+    }
+
+    let v2 = QuadTree::from_buf(&buf, res, false)
+        .collect_compact()
+        .unwrap();
+    let dec = LeafParserV2::with_resolution(v2.as_raw_slice(), res).unwrap();
+    assert_eq!(
+        dec.clone().drawable().size(),
+        Size::new(res.width() as u32, res.height() as u32)
+    );
+
+    let mut display = DumpableDisplay::new(res);
+    dec.drawable().draw(&mut display).unwrap();
+    assert_eq!(expected.buf, display.buf);
+
+    let tree = QuadTree::from_buf(&buf, res, false);
+    let v3 = tree.collect_compact_bitmap(&tree);
+    // no sector is uniform, bitmaps beat branches down to single pixels
+    assert!(v3.len() <= v2.len());
+
+    let mut display = DumpableDisplay::new(res);
+    let dec = LeafParserV3::with_resolution(v3.as_raw_slice(), res).unwrap();
+    dec.drawable().draw(&mut display).unwrap();
+    assert_eq!(expected.buf, display.buf);
+
+    let v4 = tree.collect_compact_entropy().unwrap();
+    assert!(v4.len() <= v2.as_raw_slice().len());
+    let mut display = DumpableDisplay::new(res);
+    let dec = LeafParserV4::with_resolution(&v4, res).unwrap();
+    dec.drawable().draw(&mut display).unwrap();
+    assert_eq!(expected.buf, display.buf);
+
+    if res.depth() <= 7 {
+        let mut v1 = Vec::new();
+        QuadTree::from_buf(&buf, res, true)
+            .store_packed(&mut v1)
+            .unwrap();
+
+        let mut display = DumpableDisplay::new(res);
+        let dec = LeafParserV1::with_resolution(&v1, res).unwrap();
+        dec.drawable().draw(&mut display).unwrap();
+        assert_eq!(expected.buf, display.buf);
+    }
+}
+
+#[test]
+fn enc_then_draw_resolutions() {
+    for (w, h) in [(96, 96), (128, 32), (256, 64), (64, 64), (20, 13), (1, 1)] {
+        enc_then_draw_sized(Resolution::new(w, h).unwrap());
+    }
+}
+
+/// Checks that scanning the buffer gives the same frames as building the tree.
+fn scan_matches_tree(buf: &[u8], res: Resolution) {
+    use crate::scan::{self, ScanError};
+
+    let mut compact = Vec::new();
+    let len = scan::store_compact(buf, res, &mut compact).unwrap();
+    let tree = QuadTree::from_buf(buf, res, false);
+    assert_eq!(compact, tree.collect_compact().unwrap().as_raw_slice());
+    assert_eq!(len, compact.len());
+
+    let mut packed = Vec::new();
+    let scanned = scan::store_packed(buf, res, &mut packed);
+    let mut expected = Vec::new();
+    let stored = QuadTree::from_buf(buf, res, true).store_packed(&mut expected);
+    if res.depth() <= 7 {
+        assert_eq!(scanned, Ok(stored.unwrap()));
+        assert_eq!(packed, expected);
+    } else {
+        assert_eq!(scanned, Err(ScanError::UnsupportedResolution));
+        assert_eq!(stored.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert!(expected.is_empty());
+    }
+}
+
+#[test]
+fn scan() {
+    use crate::scan::{self, ScanError, SliceSink};
+
+    scan_matches_tree(&BUF, Resolution::default());
+    for (w, h) in [(96, 96), (128, 32), (256, 64), (20, 13), (1, 1)] {
+        let res = Resolution::new(w, h).unwrap();
+        let frames = IntoIterator::into_iter([
+            synthetic_frames(res, 3),
+            sprite_frames(res, 3),
+            scrolling_frames(res, 3, (5, 3)),
+        ]);
+        for frame in frames.flatten().collect::<Vec<_>>().chunks(res.buf_len()) {
+            scan_matches_tree(frame, res);
+        }
+    }
+
+    // without an allocator
+    let mut out = heapless::Vec::<u8, 256>::new();
+    let len = scan::store_packed(&BUF, Resolution::default(), &mut out).unwrap();
+    assert_eq!(&out[..], EXPECTED_BYTES_LINEAR);
+    assert_eq!(len, out.len());
+    let mut out = [0; 4];
+    let mut sink = SliceSink::new(&mut out);
+    assert_eq!(
+        scan::store_compact(&BUF, Resolution::default(), &mut sink),
+        Err(ScanError::Full)
+    );
+    assert_eq!(sink.into_written(), &EXPECTED_BYTES_COMPACT[..4]);
+}
+
+#[test]
+fn raw_framebuffer() {
+    for (w, h) in [(96, 96), (20, 13), (128, 64), (3, 1)] {
+        let res = Resolution::new(w, h).unwrap();
+        let input = synthetic_frames(res, 1);
+
+        let tree = QuadTree::from_buf(&input, res, false);
+        let v3 = tree.collect_compact_bitmap(&tree);
+        let dec = LeafParserV3::with_resolution(v3.as_raw_slice(), res).unwrap();
+
+        let mut rows = vec![0; Layout::RowMajor.buf_len(res)];
+        let mut fb = Framebuffer::new(&mut rows, res, Layout::RowMajor).unwrap();
+        fb.draw(&dec).unwrap();
+        assert_eq!(input, rows);
+
+        let mut pages = vec![0; Layout::Pages.buf_len(res)];
+        let mut fb = Framebuffer::new(&mut pages, res, Layout::Pages).unwrap();
+        fb.draw(&dec).unwrap();
+        let rows = Framebuffer::new(&mut rows, res, Layout::RowMajor).unwrap();
+        for (x, y) in (0..w as u32).flat_map(|x| (0..h as u32).map(move |y| (x, y))) {
+            assert_eq!(fb.get_pixel(x, y), rows.get_pixel(x, y));
+        }
+
+        if res.depth() <= 7 {
+            let mut v1 = Vec::new();
+            QuadTree::from_buf(&input, res, true)
+                .store_packed(&mut v1)
+                .unwrap();
+            let mut rows = vec![0xff; Layout::RowMajor.buf_len(res)];
+            let mut fb = Framebuffer::new(&mut rows, res, Layout::RowMajor).unwrap();
+            fb.draw(&LeafParserV1::with_resolution(&v1, res).unwrap())
+                .unwrap();
+            // the padding bits at the end of the rows are left alone
+            let mask: BitVec<u8, Msb0> = (0..res.stride() * 8)
+                .map(|x| x < res.width() as usize)
+                .collect();
+            for (row, input) in rows.chunks(res.stride()).zip(input.chunks(res.stride())) {
+                for ((a, b), m) in row.iter().zip(input).zip(mask.as_raw_slice()) {
+                    assert_eq!(a & m, b & m);
+                }
+            }
+        }
+    }
+
+    assert!(Framebuffer::new(&mut [0; 10], Resolution::default(), Layout::Pages).is_none());
+}
+
 fn read_test_frames() -> Vec<u8> {
     let mut buf = Vec::new();
     File::open("test_data/frames.bin")
@@ -140,36 +425,721 @@ fn encode_test_frames<E: Encode>(iframe_int: u16) -> (Vec<u8>, Vec<u8>) {
     (input, output)
 }
 
+/// A square bouncing over vertical stripes
+fn synthetic_frames(res: Resolution, count: usize) -> Vec<u8> {
+    let (w, h) = (res.width() as usize, res.height() as usize);
+    let mut out = BitVec::<u8, Msb0>::repeat(false, res.buf_len() * 8 * count);
+    let stride = res.stride() * 8;
+
+    for (i, frame) in out.chunks_mut(res.buf_len() * 8).enumerate() {
+        let (sq_x, sq_y) = ((i * 3) % w, (i * 2) % h);
+        for y in 0..h {
+            for x in 0..w {
+                let stripe = (x + i) % 16 < 3;
+                let square = (sq_x..sq_x + 20).contains(&x) && (sq_y..sq_y + 20).contains(&y);
+                frame.set(y * stride + x, stripe ^ square);
+            }
+        }
+    }
+    out.into_vec()
+}
+
+#[test]
+fn video_header_pack_then_parse() {
+    let mut header = VideoHeader::new(
+        TreeFormat::Compact,
+        Resolution::new(96, 96).unwrap(),
+        24,
+        30,
+    );
+    header.frame_count = 1234;
+
+    assert_eq!(VideoHeader::parse(&header.to_bytes()).unwrap(), header);
+
+    let mut bad_magic = header.to_bytes();
+    bad_magic[0] = b'X';
+    assert!(VideoHeader::parse(&bad_magic).is_err());
+    assert!(VideoHeader::parse(&header.to_bytes()[..10]).is_err());
+
+    // streams with frame headers are version 2, older ones stay readable by version 1 decoders
+    assert_eq!(header.to_bytes()[4], 1);
+    header.motion = true;
+    let bytes = header.to_bytes();
+    assert_eq!(bytes[4], 2);
+    assert_eq!(VideoHeader::parse(&bytes).unwrap(), header);
+    let mut future = bytes;
+    future[4] = 3;
+    assert!(VideoHeader::parse(&future).is_err());
+}
+
+const FORMATS: [TreeFormat; 4] = [
+    TreeFormat::Packed,
+    TreeFormat::Compact,
+    TreeFormat::CompactBitmap,
+    TreeFormat::CompactEntropy,
+];
+
+/// How `encode_video` sets up the encoder
+#[derive(Debug, Clone, Copy)]
+struct VideoSettings {
+    i_frame_interval: u16,
+    indexed: bool,
+    frame_count: u32,
+    /// Encode with `MotionEncoder`
+    motion: bool,
+    scene_cut: Option<u8>,
+    lossy: Lossy,
+    rate_limit: Option<RateLimit>,
+}
+
+impl Default for VideoSettings {
+    fn default() -> Self {
+        Self {
+            i_frame_interval: 8,
+            indexed: false,
+            frame_count: 0,
+            motion: false,
+            scene_cut: None,
+            lossy: Lossy::default(),
+            rate_limit: None,
+        }
+    }
+}
+
+/// A container and what the encoder reported about its frames
+struct Encoded {
+    output: Vec<u8>,
+    errors: Vec<PixelError>,
+    rate_reports: Vec<RateReport>,
+}
+
+/// Encodes `input` into a container with the encoder of `format`.
+fn encode_video(
+    format: TreeFormat,
+    res: Resolution,
+    input: &[u8],
+    settings: VideoSettings,
+) -> Encoded {
+    match format {
+        TreeFormat::Packed => encode_with::<EncoderV1>(res, input, settings),
+        TreeFormat::Compact => encode_with::<EncoderV2>(res, input, settings),
+        TreeFormat::CompactBitmap => encode_with::<EncoderV3>(res, input, settings),
+        TreeFormat::CompactEntropy => encode_with::<EncoderV4>(res, input, settings),
+    }
+}
+
+fn encode_with<E: Encode>(res: Resolution, input: &[u8], settings: VideoSettings) -> Encoded {
+    if settings.motion {
+        // the test videos move by at most 3 pixels, keep the searches short in debug builds
+        let encoder = MotionEncoder::new(E::default(), 4);
+        encode_into(res, input, settings, encoder)
+    } else {
+        encode_into(res, input, settings, E::default())
+    }
+}
+
+fn encode_into<E: Encode>(
+    res: Resolution,
+    input: &[u8],
+    settings: VideoSettings,
+    encoder: E,
+) -> Encoded {
+    let mut header = VideoHeader::new(E::FORMAT, res, 25, settings.i_frame_interval);
+    header.indexed = settings.indexed;
+    header.frame_count = settings.frame_count;
+
+    let mut enc = VideoEncoder::<_, E>::with_header(Vec::new(), header).unwrap();
+    *enc.encoder_mut() = encoder;
+    enc.set_scene_cut(settings.scene_cut);
+    enc.set_lossy(settings.lossy);
+    enc.set_rate_limit(settings.rate_limit);
+    enc.write_all(input).unwrap();
+    enc.flush().unwrap();
+    let errors = enc.errors().to_vec();
+    let rate_reports = enc.rate_reports().to_vec();
+    Encoded {
+        output: enc.finish().unwrap(),
+        errors,
+        rate_reports,
+    }
+}
+
+/// What the decoder shows of a container, drawn into a raw framebuffer
+struct Decoded {
+    /// Wrong pixels of every displayed frame, against the input
+    errors: Vec<PixelError>,
+    /// Frames moving the previous one
+    shifted: usize,
+    /// Squares copied from the previous frame
+    copies: usize,
+}
+
+/// Decodes a container, checking that it shows as many frames as the input has.
+fn decode_and_measure(output: &[u8], input: &[u8]) -> Decoded {
+    let video = Video::new(output).unwrap();
+    let res = video.header().resolution;
+    let mut buf = vec![0; res.buf_len()];
+    let mut fb = Framebuffer::new(&mut buf, res, Layout::RowMajor).unwrap();
+    let mut frames = input.chunks(res.buf_len());
+    let mut decoded = Decoded {
+        errors: Vec::new(),
+        shifted: 0,
+        copies: 0,
+    };
+    for dec in video.frames() {
+        let dec = dec.unwrap();
+        decoded.shifted += dec.shift().is_some() as usize;
+        decoded.copies += dec.copies().count();
+        fb.draw(&dec).unwrap();
+        if dec.flush_after() {
+            let frame = frames.next().unwrap();
+            decoded
+                .errors
+                .push(PixelError::compare(fb.as_bytes(), frame, res));
+        }
+    }
+    assert!(frames.next().is_none());
+    decoded
+}
+
+#[test]
+fn container() {
+    use TreeFormat::*;
+
+    for (format, (w, h), i_frame_interval) in [
+        (Packed, (96, 96), 5),
+        (Packed, (128, 32), 1),
+        (Compact, (256, 64), 5),
+        (Compact, (96, 96), 60),
+        (CompactBitmap, (256, 64), 5),
+        (CompactBitmap, (20, 13), 60),
+        (CompactEntropy, (256, 64), 5),
+        (CompactEntropy, (20, 13), 60),
+    ] {
+        let res = Resolution::new(w, h).unwrap();
+        let input = synthetic_frames(res, 20);
+        let settings = VideoSettings {
+            i_frame_interval,
+            frame_count: 20,
+            ..Default::default()
+        };
+        let output = encode_video(format, res, &input, settings).output;
+
+        let mut header = VideoHeader::new(format, res, 25, i_frame_interval);
+        header.frame_count = 20;
+        let video = Video::new(&output).unwrap();
+        assert_eq!(*video.header(), header);
+        compare_original_and_encoded(&input, video.frames(), res);
+    }
+}
+
+/// Seeks to `target`, drawing through embedded-graphics and then into a raw framebuffer,
+/// and checks the frame shown after it against the input.
+fn seek_and_check<'a, D: ParseFrame<'a>>(
+    frames: &mut VideoSlice<'a, D>,
+    input: &[u8],
+    target: u32,
+) {
+    let res = frames.resolution();
+    let expected = &input[target as usize * res.buf_len()..][..res.buf_len()];
+
+    let mut display = DumpableDisplay::new(res);
+    assert!(frames.seek(target, &mut display).unwrap());
+    for dec in frames.by_ref() {
+        let dec = dec.unwrap();
+        let flush = dec.flush_after();
+        dec.drawable().draw(&mut display).unwrap();
+        if flush {
+            break;
+        }
+    }
+    assert_eq!(expected, display.buf.as_raw_slice(), "frame {}", target);
+
+    let mut raw = vec![0; res.buf_len()];
+    let mut fb = Framebuffer::new(&mut raw, res, Layout::RowMajor).unwrap();
+    assert!(frames.seek_framebuffer(target, &mut fb).unwrap());
+    for dec in frames.by_ref() {
+        let dec = dec.unwrap();
+        fb.draw(&dec).unwrap();
+        if dec.flush_after() {
+            break;
+        }
+    }
+    assert_eq!(expected, fb.as_bytes(), "frame {}", target);
+}
+
+#[test]
+fn seek() {
+    let res = Resolution::new(96, 64).unwrap();
+    let input = synthetic_frames(res, 30);
+    for format in FORMATS {
+        for indexed in [true, false] {
+            let settings = VideoSettings {
+                indexed,
+                ..Default::default()
+            };
+            let output = encode_video(format, res, &input, settings).output;
+            let video = Video::new(&output).unwrap();
+            let mut frames = video.frames();
+            for target in [13, 2, 29, 8, 16, 0] {
+                seek_and_check(&mut frames, &input, target);
+            }
+            let mut display = DumpableDisplay::new(res);
+            assert!(!frames.seek(30, &mut display).unwrap());
+        }
+    }
+}
+
+#[test]
+fn lossy_video() {
+    let res = Resolution::new(96, 64).unwrap();
+    let mut input = synthetic_frames(res, 10);
+    // isolated pixels cost a branch all the way down
+    for (i, byte) in input.iter_mut().enumerate().step_by(37) {
+        *byte ^= 0x80 >> (i % 8);
+    }
+    let encode = |lossy| {
+        let settings = VideoSettings {
+            i_frame_interval: 5,
+            lossy,
+            ..Default::default()
+        };
+        encode_video(TreeFormat::Compact, res, &input, settings)
+    };
+
+    let lossless = encode(Lossy::default());
+    assert!(lossless.errors.iter().all(|e| e.wrong == 0));
+    let lossy = encode(Lossy::global(2));
+    assert!(lossy.output.len() < lossless.output.len() * 3 / 4);
+    assert_eq!(lossy.errors.len(), 10);
+    assert!(lossy.errors.iter().all(|e| e.wrong > 0));
+
+    // the errors are what the decoder shows
+    assert_eq!(
+        decode_and_measure(&lossy.output, &input).errors,
+        lossy.errors
+    );
+}
+
+#[test]
+fn temporal_lossy_video() {
+    use crate::enc::video::TemporalLossy;
+
+    let res = Resolution::new(96, 64).unwrap();
+    // a still picture with flickering pixels, different in every frame
+    let mut input = synthetic_frames(res, 1).repeat(12);
+    for (i, byte) in input.iter_mut().enumerate().step_by(29) {
+        *byte ^= 0x80 >> (i % 7);
+    }
+    let encode = |lossy| {
+        let header = VideoHeader::new(EncoderV2::FORMAT, res, 25, 6);
+        let mut enc =
+            VideoEncoder::<_, TemporalLossy<EncoderV2>>::with_header(Vec::new(), header).unwrap();
+        enc.encoder_mut().set_lossy(lossy);
+        enc.write_all(&input).unwrap();
+        enc.flush().unwrap();
+        let errors = enc.errors().to_vec();
+        (enc.finish().unwrap(), errors)
+    };
+
+    // lossless is the same as the inner encoder
+    let header = VideoHeader::new(EncoderV2::FORMAT, res, 25, 6);
+    let mut enc = VideoEncoder::<_, EncoderV2>::with_header(Vec::new(), header).unwrap();
+    enc.write_all(&input).unwrap();
+    let lossless = enc.finish().unwrap();
+    assert_eq!(encode(Lossy::default()).0, lossless);
+
+    // at most 2 wrong pixels in every 8x8 square
+    let (lossy, errors) = encode(Lossy::per_depth(&[0, 0, 0, 0, 2]));
+    assert!(lossy.len() < lossless.len() * 3 / 4);
+    // I-frames are exact
+    assert_eq!(errors[0].wrong, 0);
+    assert_eq!(errors[6].wrong, 0);
+    assert!(errors.iter().any(|e| e.wrong > 0));
+    assert!(errors.iter().all(|e| e.wrong <= 2 * 12 * 8));
+
+    // the errors are what the decoder shows, the skipped changes don't drift
+    assert_eq!(decode_and_measure(&lossy, &input).errors, errors);
+}
+
+/// Stripes and blobs seen through a window moving by `step` every frame
+fn scrolling_frames(res: Resolution, count: usize, step: (usize, usize)) -> Vec<u8> {
+    let (w, h) = (res.width() as usize, res.height() as usize);
+    let mut out = BitVec::<u8, Msb0>::repeat(false, res.buf_len() * 8 * count);
+    let stride = res.stride() * 8;
+
+    for (i, frame) in out.chunks_mut(res.buf_len() * 8).enumerate() {
+        for y in 0..h {
+            for x in 0..w {
+                let (px, py) = (x + step.0 * i, y + step.1 * i);
+                let set = ((px / 7) ^ (py / 5)) % 3 == 0 || (px + 2 * py) % 23 < 2;
+                frame.set(y * stride + x, set);
+            }
+        }
+    }
+    out.into_vec()
+}
+
+/// Checkered balls moving by `(3, 2)` and `(-2, 1)` every frame inside a static border
+fn sprite_frames(res: Resolution, count: usize) -> Vec<u8> {
+    let (w, h) = (res.width() as i32, res.height() as i32);
+    let mut out = BitVec::<u8, Msb0>::repeat(false, res.buf_len() * 8 * count);
+    let stride = res.stride() * 8;
+
+    for (i, frame) in out.chunks_mut(res.buf_len() * 8).enumerate() {
+        let i = i as i32;
+        let sprites = [(4 + 3 * i, 6 + 2 * i), (w - 30 - 2 * i, 20 + i)];
+        for y in 0..h {
+            for x in 0..w {
+                let border = x == 0 || y == 0 || x == w - 1 || y == h - 1;
+                let sprite = sprites.iter().any(|(sx, sy)| {
+                    let (dx, dy) = (x - sx, y - sy);
+                    let checker = (dx / 2 + dy / 3) % 2 == 0;
+                    (dx - 14).pow(2) + (dy - 14).pow(2) < 196 && checker
+                });
+                frame.set(y as usize * stride + x as usize, border || sprite);
+            }
+        }
+    }
+    out.into_vec()
+}
+
+#[test]
+fn motion_video() {
+    let res = Resolution::new(96, 64).unwrap();
+    let scrolling = scrolling_frames(res, 10, (2, 1));
+    let sprites = sprite_frames(res, 10);
+    let motion = VideoSettings {
+        motion: true,
+        ..Default::default()
+    };
+    for format in FORMATS {
+        let plain = encode_video(format, res, &scrolling, Default::default()).output;
+        let moved = encode_video(format, res, &scrolling, motion).output;
+        assert!(Video::new(&moved).unwrap().header().motion);
+        let decoded = decode_and_measure(&moved, &scrolling);
+        assert!(decoded.errors.iter().all(|e| e.wrong == 0));
+        assert!(moved.len() < plain.len() / 2);
+        // every P-frame scrolls
+        assert_eq!(decoded.shifted, 8);
+
+        let plain = encode_video(format, res, &sprites, Default::default()).output;
+        let moved = encode_video(format, res, &sprites, motion).output;
+        let decoded = decode_and_measure(&moved, &sprites);
+        assert!(decoded.errors.iter().all(|e| e.wrong == 0));
+        assert!(moved.len() < plain.len());
+        assert!(decoded.copies > 0);
+    }
+}
+
+#[test]
+fn rate_limit() {
+    let res = Resolution::new(64, 48).unwrap();
+    let input = scrolling_frames(res, 8, (2, 1));
+
+    for format in FORMATS {
+        let encode = |limit| {
+            let settings = VideoSettings {
+                i_frame_interval: 4,
+                rate_limit: Some(limit),
+                ..Default::default()
+            };
+            let encoded = encode_video(format, res, &input, settings);
+            let reports = &encoded.rate_reports;
+            assert_eq!(reports.len(), 8);
+            assert!(reports.iter().all(|r| !r.over_budget()));
+            let sizes: usize = reports.iter().map(|r| r.size).sum();
+            assert_eq!(sizes, encoded.output.len() - VideoHeader::LEN);
+            // the errors are what the decoder shows
+            let decoded = decode_and_measure(&encoded.output, &input);
+            assert_eq!(decoded.errors, encoded.errors);
+            encoded.rate_reports
+        };
+
+        let reports = encode(RateLimit::Frame(100));
+        assert!(reports.iter().all(|r| r.size <= 100));
+        assert!(reports.iter().any(RateReport::degraded));
+
+        let reports = encode(RateLimit::Average {
+            bytes_per_second: 1000,
+            frame_rate: 10,
+        });
+        // 0.8 seconds, the bytes saved by small frames are only spent later
+        assert!(reports.iter().map(|r| r.size).sum::<usize>() <= 800);
+        assert!(reports.iter().any(RateReport::degraded));
+    }
+}
+
+/// Frames of the seek index at the end of a container
+fn indexed_frames(output: &[u8]) -> Vec<u32> {
+    let (rest, tail) = output.split_at(output.len() - 8);
+    assert_eq!(tail[4..], VideoHeader::INDEX_MAGIC);
+    let count = u32::from_le_bytes(<[u8; 4]>::try_from(&tail[..4]).unwrap()) as usize;
+    rest[rest.len() - count * 8..]
+        .chunks_exact(8)
+        .map(|e| u32::from_le_bytes(<[u8; 4]>::try_from(&e[..4]).unwrap()))
+        .collect()
+}
+
+#[test]
+fn scene_cut() {
+    let res = Resolution::new(96, 64).unwrap();
+    // a still scene, then its negative
+    let mut input = scrolling_frames(res, 10, (0, 0));
+    input.extend(input.clone().iter().map(|b| !b));
+
+    for format in FORMATS {
+        let encode = |scene_cut, i_frame_interval| {
+            let settings = VideoSettings {
+                i_frame_interval,
+                indexed: true,
+                scene_cut,
+                ..Default::default()
+            };
+            encode_video(format, res, &input, settings).output
+        };
+        assert_eq!(indexed_frames(&encode(None, 100)), [0]);
+        let output = encode(Some(50), 100);
+        assert_eq!(indexed_frames(&output), [0, 10]);
+        // the interval counts from the cut
+        assert_eq!(indexed_frames(&encode(Some(50), 8)), [0, 8, 10, 18]);
+
+        let video = Video::new(&output).unwrap();
+        let mut frames = video.frames();
+        for target in [12, 19, 3, 10] {
+            seek_and_check(&mut frames, &input, target);
+        }
+    }
+}
+
+#[test]
+fn stream() {
+    let res = Resolution::new(96, 64).unwrap();
+    let input = synthetic_frames(res, 12);
+    for format in FORMATS {
+        // without a frame count, the stream stops at the seek index
+        for (indexed, frame_count) in [(format == TreeFormat::CompactEntropy, 12), (true, 0)] {
+            let settings = VideoSettings {
+                i_frame_interval: 5,
+                indexed,
+                frame_count,
+                ..Default::default()
+            };
+            let output = encode_video(format, res, &input, settings).output;
+
+            // pull a few bytes at a time, like from external flash
+            let mut source = &output[..];
+            let read = |buf: &mut [u8]| {
+                let len = min(buf.len(), 3);
+                source.read(&mut buf[..len])
+            };
+            let mut stream = VideoStream::<_, 1024>::with_header(read).unwrap();
+            let video = Video::new(&output).unwrap();
+            assert_eq!(stream.header(), Some(video.header()));
+
+            let mut frames = video.frames();
+            while let Some(dec) = stream.next_any() {
+                let expected = frames.next().unwrap().unwrap();
+                assert!(dec.unwrap().iter().eq(expected.iter()));
+            }
+            assert!(frames.next().is_none());
+        }
+
+        // the index of a video without frames has no entries
+        let settings = VideoSettings {
+            indexed: true,
+            ..Default::default()
+        };
+        let output = encode_video(format, res, &[], settings).output;
+        let mut source = &output[..];
+        let mut stream =
+            VideoStream::<_, 1024>::with_header(|buf: &mut [u8]| source.read(buf)).unwrap();
+        assert!(stream.next_any().is_none());
+    }
+
+    let mut frames = Vec::new();
+    let mut enc = VideoEncoder::<_, EncoderV2>::new(&mut frames, 30);
+    enc.write_all(&synthetic_frames(Resolution::default(), 2))
+        .unwrap();
+    enc.flush().unwrap();
+
+    let mut source = &frames[..];
+    let mut stream = VideoStream::<_, 1024>::new(|buf: &mut [u8]| source.read(buf));
+    assert!(stream.next_any().unwrap().is_err());
+    assert!(stream.next_frame::<LeafParserV2>().unwrap().is_ok());
+
+    let mut source = &frames[..];
+    let mut stream = VideoStream::<_, 16>::new(|buf: &mut [u8]| source.read(buf));
+    assert!(matches!(
+        stream.next_frame::<LeafParserV2>(),
+        Some(Err(ReadError::FrameTooLarge))
+    ));
+    assert!(stream.next_frame::<LeafParserV2>().is_none());
+}
+
+#[cfg(feature = "image")]
+#[test]
+fn export() {
+    use crate::{
+        dec::export::{render, write_gif, write_pbm, write_png, Rendered},
+        enc::input::{detect, load_image},
+    };
+    use image::{codecs::gif::GifDecoder, AnimationDecoder};
+
+    let res = Resolution::new(20, 13).unwrap();
+    let input = synthetic_frames(res, 7);
+    let header = VideoHeader::new(EncoderV2::FORMAT, res, 30, 3);
+    let mut enc = VideoEncoder::<_, EncoderV2>::with_header(Vec::new(), header).unwrap();
+    enc.write_all(&input).unwrap();
+    let output = enc.finish().unwrap();
+    let video = Video::new(&output).unwrap();
+
+    let frames: Vec<_> = Rendered::new(video.frames()).map(Result::unwrap).collect();
+    assert_eq!(frames.concat(), input);
+    let first = render(&video.frames().next().unwrap().unwrap()).unwrap();
+    assert_eq!(first, input[..res.buf_len()]);
+
+    // PBM pixels are black when set, lit ones are white
+    let mut pbm = Vec::new();
+    write_pbm(&first, res, &mut pbm).unwrap();
+    let inverted: Vec<u8> = first.iter().map(|b| !b).collect();
+    assert!(pbm.ends_with(&inverted));
+
+    // stills read back as the same bitmap
+    for still in [
+        |buf: &[u8], res, out: &mut Vec<u8>| write_pbm(buf, res, out).unwrap(),
+        |buf: &[u8], res, out: &mut Vec<u8>| write_png(buf, res, out).unwrap(),
+    ] {
+        let mut out = Vec::new();
+        still(&first, res, &mut out);
+        let image = load_image(&out, detect(&out).unwrap(), res, Default::default()).unwrap();
+        assert_eq!(image, first);
+    }
+
+    let mut gif = Vec::new();
+    write_gif(video.frames(), 30, &mut gif).unwrap();
+    let decoded = GifDecoder::new(&gif[..])
+        .unwrap()
+        .into_frames()
+        .collect_frames()
+        .unwrap();
+    assert_eq!(decoded.len(), 7);
+    let delays: Vec<_> = decoded
+        .iter()
+        .map(|f| f.delay().numer_denom_ms())
+        .map(|(n, d)| n / d)
+        .collect();
+    assert_eq!(delays, [30, 30, 40, 30, 30, 40, 30]);
+    for (frame, expected) in decoded.into_iter().zip(input.chunks(res.buf_len())) {
+        let luma: Vec<u8> = image::DynamicImage::ImageRgba8(frame.into_buffer())
+            .into_luma8()
+            .into_raw();
+        assert_eq!(Binarize::default().apply(&luma, res), expected);
+    }
+}
+
 #[test]
 fn bad_apple_v2() {
     let (input, output) = encode_test_frames::<EncoderV2>(60);
-    compare_original_and_encoded::<LeafParserV2>(&input, &output);
+    let frames = VideoSlice::<LeafParserV2>::new(&output);
+    compare_original_and_encoded(&input, frames, Resolution::default());
+}
+
+#[test]
+fn bad_apple_v3() {
+    let (input, output) = encode_test_frames::<EncoderV3>(60);
+    let frames = VideoSlice::<LeafParserV3>::new(&output);
+    compare_original_and_encoded(&input, frames, Resolution::default());
+}
+
+#[test]
+fn bad_apple_v4() {
+    let (input, output) = encode_test_frames::<EncoderV4>(60);
+    let frames = VideoSlice::<LeafParserV4>::new(&output);
+    compare_original_and_encoded(&input, frames, Resolution::default());
+
+    let (_, v2) = encode_test_frames::<EncoderV2>(60);
+    assert!(output.len() < v2.len());
 }
 
 #[test]
 fn bad_apple_v1() {
     let (input, output) = encode_test_frames::<EncoderV1>(1);
-    compare_original_and_encoded::<LeafParserV1>(&input, &output);
+    let frames = VideoSlice::<LeafParserV1>::new(&output);
+    compare_original_and_encoded(&input, frames, Resolution::default());
 }
 
 #[test]
 fn bad_apple_pframes_v1() {
     let (input, output) = encode_test_frames::<EncoderV1>(60);
-    compare_original_and_encoded::<LeafParserV1>(&input, &output);
+    let frames = VideoSlice::<LeafParserV1>::new(&output);
+    compare_original_and_encoded(&input, frames, Resolution::default());
+}
+
+/// Encodes with both kinds of p-frames, checks the XOR ones decode to `input` and returns the
+/// sizes of the split and XOR streams.
+fn split_and_xor(input: &[u8], res: Resolution, iframe_int: u16) -> (usize, usize) {
+    use crate::enc::video::PFrames;
+
+    let encode = |p_frames| {
+        let mut output = Vec::with_capacity(input.len() / 2);
+        let mut enc = VideoEncoder::<_, EncoderV1>::new(&mut output, iframe_int);
+        enc.encoder_mut().set_p_frames(p_frames);
+        enc.write_all(input).unwrap();
+        drop(enc);
+        output
+    };
+    let split = encode(PFrames::Split);
+    let xor = encode(PFrames::Xor);
+
+    let mut buf = vec![0; res.buf_len()];
+    let mut fb = Framebuffer::new(&mut buf, res, Layout::RowMajor).unwrap();
+    let mut frames = input.chunks(res.buf_len());
+    for dec in VideoSlice::<LeafParserV1>::new(&xor) {
+        let dec = dec.unwrap();
+        fb.draw(&dec).unwrap();
+        if dec.flush_after() {
+            assert_eq!(fb.as_bytes(), frames.next().unwrap());
+        }
+    }
+    assert!(frames.next().is_none());
+    (split.len(), xor.len())
 }
 
-fn compare_original_and_encoded<'a, D: Decoder<'a> + Clone + core::fmt::Debug>(
+#[test]
+fn xor_pframes_v1() {
+    let res = Resolution::default();
+    let (split, xor) = split_and_xor(&synthetic_frames(res, 12), res, 5);
+    assert!(xor < split);
+}
+
+#[test]
+fn bad_apple_xor_v1() {
+    let (split, xor) = split_and_xor(&read_test_frames(), Resolution::default(), 60);
+    assert!(
+        xor < split,
+        "XOR p-frames: {} bytes, split: {} bytes",
+        xor,
+        split
+    );
+}
+
+fn compare_original_and_encoded<'a, D: ParseFrame<'a> + Clone + core::fmt::Debug>(
     original: &[u8],
-    encoded: &'a [u8],
+    mut enc_iter: VideoSlice<'a, D>,
+    res: Resolution,
 ) {
-    let mut display = DumpableDisplay::default();
-    let bitmaps = original.chunks(1024).take_while(|c| c.len() == 1024);
-    let mut enc_iter: VideoSlice<D> = VideoSlice::new(&encoded);
+    let mut display = DumpableDisplay::new(res);
+    let bitmaps = original
+        .chunks(res.buf_len())
+        .take_while(|c| c.len() == res.buf_len());
 
     for (i, frame) in bitmaps.enumerate() {
         let mut last_leaves = [None, None];
 
         for leaves in enc_iter.by_ref() {
+            let leaves = leaves.unwrap();
+            leaves.validate().unwrap();
             leaves.clone().drawable().draw(&mut display).unwrap();
 
             if leaves.flush_after() {
@@ -181,15 +1151,14 @@ fn compare_original_and_encoded<'a, D: Decoder<'a> + Clone + core::fmt::Debug>(
         }
 
         if frame != display.buf.as_raw_slice() {
-            dump_to_image(&frame, "test_data/orig.png");
-            dump_to_image(&display.buf.as_raw_slice(), "test_data/decoded.png");
+            dump_to_image(frame, res, "test_data/orig.png");
+            dump_to_image(display.buf.as_raw_slice(), res, "test_data/decoded.png");
 
             let mut leaf_dump = File::create("test_data/leaves.txt").unwrap();
             let leaves: Vec<_> = last_leaves
                 .iter()
                 .flatten()
-                .map(Decoder::iter)
-                .flatten()
+                .flat_map(Decoder::iter)
                 .collect();
             write!(&mut leaf_dump, "Leaf dump:\n{leaves:#?}").unwrap();
 
@@ -198,14 +1167,16 @@ fn compare_original_and_encoded<'a, D: Decoder<'a> + Clone + core::fmt::Debug>(
     }
 }
 
-fn dump_to_image(buf: &[u8], path: &str) {
+fn dump_to_image(buf: &[u8], res: Resolution, path: &str) {
     let settings = OutputSettingsBuilder::new()
         .theme(embedded_graphics_simulator::BinaryColorTheme::LcdBlue)
         .build();
-    let mut dump_display =
-        SimulatorDisplay::with_default_color(Size::new(WIDTH, HEIGHT), BinaryColor::Off);
+    let mut dump_display = SimulatorDisplay::with_default_color(
+        Size::new(res.width() as u32, res.height() as u32),
+        BinaryColor::Off,
+    );
 
-    ImageRaw::new_binary(buf, WIDTH)
+    ImageRaw::new_binary(buf, res.width() as u32)
         .draw(&mut dump_display)
         .unwrap();
 